@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::remote::Repository;
+use crate::{gitea, github, gitlab};
+
+/// Which kind of forge hosts the repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForgeKind {
+    GitHub,
+    GitLab,
+    /// Gitea or Forgejo, which share an API
+    Gitea,
+}
+
+impl ForgeKind {
+    /// Guess the forge from the host name, anything that looks like neither GitLab
+    /// nor Gitea/Forgejo is GitHub
+    pub fn detect(host: &str) -> Self {
+        let host = host.split(':').next().unwrap_or(host);
+        let parts: Vec<&str> = host.split('.').collect();
+        if parts.contains(&"gitlab") {
+            ForgeKind::GitLab
+        } else if host == "codeberg.org" || parts.contains(&"gitea") || parts.contains(&"forgejo") {
+            ForgeKind::Gitea
+        } else {
+            ForgeKind::GitHub
+        }
+    }
+
+    /// The API URL to use when none is configured
+    pub fn default_api_url(self, host: &str) -> String {
+        match self {
+            ForgeKind::GitHub => github::api_url(host),
+            ForgeKind::GitLab => gitlab::api_url(host),
+            ForgeKind::Gitea => gitea::api_url(host),
+        }
+    }
+}
+
+impl FromStr for ForgeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "github" => Ok(ForgeKind::GitHub),
+            "gitlab" => Ok(ForgeKind::GitLab),
+            "gitea" | "forgejo" => Ok(ForgeKind::Gitea),
+            _ => Err(format!(
+                "unknown forge '{s}', expected one of: github, gitlab, gitea, forgejo"
+            )),
+        }
+    }
+}
+
+/// Everything stack-prs needs from a forge. PRs (merge requests on GitLab) are identified
+/// by their web URL.
+pub trait Forge {
+    /// Name of the forge to show in messages, like "GitHub"
+    fn name(&self) -> &'static str;
+
+    /// Get the name of the repository's default branch
+    fn default_branch(&self) -> Result<String>;
+
+    /// Find the open PRs of all given branches, with their base, draft state and stack comment
+    fn fetch_prs(&self, branches: &[&str]) -> Result<PrCache>;
+
+    /// Create a PR and return its URL
+    fn create_pr(
+        &self,
+        head_branch: &str,
+        base_branch: &str,
+        title: &str,
+        body: &str,
+        draft: bool,
+    ) -> Result<String>;
+
+    /// Add a comment to a PR and return the id of the new comment
+    fn add_pr_comment(&self, pr_url: &str, comment: &str) -> Result<String>;
+
+    /// Update an existing comment on a PR
+    fn update_pr_comment(&self, pr_url: &str, comment_id: &str, comment: &str) -> Result<()>;
+
+    /// Delete a comment from a PR
+    fn delete_pr_comment(&self, pr_url: &str, comment_id: &str) -> Result<()>;
+
+    /// Change the base branch of an existing PR
+    fn edit_pr_base(&self, pr_url: &str, base_branch: &str) -> Result<()>;
+
+    /// Mark a draft PR as ready for review, or turn a PR back into a draft
+    fn set_pr_draft(&self, pr_url: &str, draft: bool) -> Result<()>;
+
+    /// Replace the title of an existing PR
+    fn edit_pr_title(&self, pr_url: &str, title: &str) -> Result<()>;
+
+    /// Replace the body of an existing PR
+    fn edit_pr_body(&self, pr_url: &str, body: &str) -> Result<()>;
+
+    /// Close a PR without merging it
+    fn close_pr(&self, pr_url: &str) -> Result<()>;
+
+    /// Find the most recent PR of each branch in any state, with its review and CI status
+    fn fetch_pr_statuses(&self, branches: &[&str]) -> Result<HashMap<String, PrStatus>>;
+}
+
+/// Connect to the forge of the given kind to open PRs in `repository`.
+/// `head_repository` is the fork the branches are pushed to, if they are not pushed to
+/// `repository` itself. `github_backend` only applies to GitHub.
+pub fn connect(
+    kind: ForgeKind,
+    github_backend: github::BackendKind,
+    repository: Repository,
+    head_repository: Option<Repository>,
+    api_url: &str,
+) -> Result<Box<dyn Forge>> {
+    match kind {
+        ForgeKind::GitHub => github::backend(github_backend, repository, head_repository, api_url),
+        ForgeKind::GitLab => Ok(Box::new(gitlab::GitLab::new(
+            &repository,
+            head_repository.as_ref(),
+            api_url,
+        )?)),
+        ForgeKind::Gitea => Ok(Box::new(gitea::Gitea::new(
+            &repository,
+            head_repository.map(|r| r.owner),
+            api_url,
+        )?)),
+    }
+}
+
+/// Information about an existing PR
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrInfo {
+    pub number: u64,
+    pub url: String,
+    pub title: String,
+    pub body: String,
+    pub base: String,
+    pub is_draft: bool,
+    pub stack_comment: Option<Comment>,
+}
+
+/// State of a PR, including ones that are no longer open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrState {
+    Open,
+    Draft,
+    Merged,
+    Closed,
+}
+
+/// Outcome of the reviews of a PR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Review {
+    Approved,
+    ChangesRequested,
+    Required,
+}
+
+/// Combined result of the CI checks on the head of a PR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checks {
+    Passing,
+    Failing,
+    Pending,
+}
+
+/// Where a PR stands, as shown by `stack-prs status`
+#[derive(Debug, Clone)]
+pub struct PrStatus {
+    pub number: u64,
+    pub url: String,
+    pub state: PrState,
+    pub review: Option<Review>,
+    pub checks: Option<Checks>,
+}
+
+/// A comment on a PR, `id` is the API id used to edit it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: String,
+    pub body: String,
+}
+
+/// The open PRs of a set of branches, fetched once and consulted for the rest of the run
+#[derive(Debug, Clone, Default)]
+pub struct PrCache {
+    prs: HashMap<String, PrInfo>,
+    /// Branches that were looked up, with or without an open PR
+    looked_up: HashSet<String>,
+}
+
+impl PrCache {
+    /// Look up the open PRs of the branches that were not looked up before, in one query
+    pub fn fetch(&mut self, forge: &dyn Forge, branches: &[&str]) -> Result<()> {
+        let mut missing: Vec<&str> = branches
+            .iter()
+            .copied()
+            .filter(|branch| !self.looked_up.contains(*branch))
+            .collect();
+        missing.dedup();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let fetched = forge.fetch_prs(&missing)?;
+        self.prs.extend(fetched.prs);
+        self.looked_up
+            .extend(missing.iter().map(|branch| branch.to_string()));
+        Ok(())
+    }
+
+    /// Get the open PR whose head is the given branch
+    pub fn get(&self, branch: &str) -> Option<&PrInfo> {
+        self.prs.get(branch)
+    }
+
+    /// Record the open PR of a branch
+    pub fn insert(&mut self, branch: &str, pr: PrInfo) {
+        self.prs.insert(branch.to_string(), pr);
+    }
+}
+
+/// Heading that identifies the stack comment among a PR's comments
+pub const STACK_COMMENT_HEADING: &str = "## Stack Information";
+
+/// The PR number is the last segment of a PR URL, like `https://github.com/owner/repo/pull/123`
+/// or `https://gitlab.com/group/project/-/merge_requests/123`
+pub fn pr_number(pr_url: &str) -> Result<u64> {
+    pr_url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .and_then(|number| number.parse().ok())
+        .with_context(|| format!("Invalid PR URL format: {pr_url}"))
+}
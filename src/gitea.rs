@@ -0,0 +1,642 @@
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::forge::{
+    pr_number, Checks, Comment, Forge, PrCache, PrInfo, PrState, PrStatus, Review,
+    STACK_COMMENT_HEADING,
+};
+use crate::http::{self, ApiError, Client};
+use crate::remote::Repository;
+
+/// Title prefix that marks a PR as work in progress, Gitea's equivalent of a draft
+const DRAFT_PREFIX: &str = "WIP: ";
+
+/// Page size for listing PRs, the maximum Gitea allows by default
+const PAGE_SIZE: usize = 50;
+
+/// Talks to the API of Gitea and its fork Forgejo (e.g. Codeberg)
+pub struct Gitea {
+    client: Client,
+    owner: String,
+    repo: String,
+    /// Owner of the fork the branches are pushed to, if not the repository itself
+    head_owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RepositoryInfo {
+    default_branch: String,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    number: u64,
+    html_url: String,
+    title: String,
+    #[serde(default)]
+    body: Option<String>,
+    base: BranchRef,
+    head: BranchRef,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    state: String,
+    #[serde(default)]
+    merged: bool,
+}
+
+#[derive(Deserialize)]
+struct BranchRef {
+    #[serde(rename = "ref")]
+    name: String,
+    #[serde(default)]
+    sha: String,
+    repo: Option<RepoRef>,
+}
+
+#[derive(Deserialize)]
+struct RepoRef {
+    owner: Owner,
+}
+
+#[derive(Deserialize)]
+struct Owner {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct PullReview {
+    state: String,
+    #[serde(default)]
+    dismissed: bool,
+    #[serde(default)]
+    stale: bool,
+}
+
+#[derive(Deserialize)]
+struct CombinedStatus {
+    state: String,
+    #[serde(default)]
+    total_count: u64,
+}
+
+#[derive(Deserialize)]
+struct IssueComment {
+    id: u64,
+    body: String,
+}
+
+#[derive(Serialize)]
+struct NewPullRequest<'a> {
+    head: &'a str,
+    base: &'a str,
+    title: &'a str,
+    body: &'a str,
+}
+
+/// The API URL of a Gitea or Forgejo host
+pub fn api_url(host: &str) -> String {
+    format!("https://{host}/api/v1")
+}
+
+impl Gitea {
+    /// Set up a client for `repository` at `api_url`, authenticated with the token from
+    /// the environment or tea's config file
+    pub fn new(repository: &Repository, head_owner: Option<String>, api_url: &str) -> Result<Self> {
+        let host = &repository.host;
+        let token = http::token(
+            host,
+            &["GITEA_TOKEN", "FORGEJO_TOKEN"],
+            tea_config_file(),
+            config_file_token,
+            &format!("tea login add --url https://{host}"),
+        )?;
+        debug!("Using Gitea API at {api_url} for {}", repository.slug());
+
+        let client = Client::new(
+            api_url,
+            vec![
+                ("Authorization".to_string(), format!("token {token}")),
+                ("Accept".to_string(), "application/json".to_string()),
+                ("User-Agent".to_string(), "stack-prs".to_string()),
+            ],
+        );
+
+        Ok(Gitea {
+            client,
+            owner: repository.owner.clone(),
+            repo: repository.name.clone(),
+            head_owner,
+        })
+    }
+
+    fn repo_path(&self) -> String {
+        format!("/repos/{}/{}", self.owner, self.repo)
+    }
+
+    fn pull_path(&self, pr_url: &str) -> Result<String> {
+        Ok(format!("{}/pulls/{}", self.repo_path(), pr_number(pr_url)?))
+    }
+
+    fn pull_request(&self, pr_url: &str) -> Result<PullRequest> {
+        self.client
+            .get(&self.pull_path(pr_url)?)
+            .with_context(|| format!("Failed to look up PR {pr_url}"))
+    }
+
+    fn edit_pull_request(&self, pr_url: &str, fields: serde_json::Value) -> Result<()> {
+        let _: serde_json::Value = self
+            .client
+            .send("PATCH", &self.pull_path(pr_url)?, &fields)
+            .with_context(|| format!("Failed to edit PR {pr_url}"))?;
+        Ok(())
+    }
+
+    /// All open PRs of the repository, page by page
+    fn open_pull_requests(&self) -> Result<Vec<PullRequest>, ApiError> {
+        let mut pull_requests = Vec::new();
+        for page in 1.. {
+            let batch = self.pull_requests_page("open", page)?;
+            let done = batch.len() < PAGE_SIZE;
+            pull_requests.extend(batch);
+            if done {
+                break;
+            }
+        }
+        Ok(pull_requests)
+    }
+
+    /// One page of the PRs in `state`, newest first
+    fn pull_requests_page(&self, state: &str, page: usize) -> Result<Vec<PullRequest>, ApiError> {
+        self.client.get(&format!(
+            "{}/pulls?state={state}&sort=newest&limit={PAGE_SIZE}&page={page}",
+            self.repo_path()
+        ))
+    }
+
+    fn is_head_owner(&self, pr: &PullRequest) -> bool {
+        let head_owner = self.head_owner.as_deref().unwrap_or(&self.owner);
+        pr.head
+            .repo
+            .as_ref()
+            .is_none_or(|repo| repo.owner.login.eq_ignore_ascii_case(head_owner))
+    }
+
+    /// The outcome of the latest review of each reviewer that still counts
+    fn review(&self, number: u64) -> Result<Option<Review>, ApiError> {
+        let reviews: Vec<PullReview> = self
+            .client
+            .get(&format!("{}/pulls/{number}/reviews", self.repo_path()))?;
+
+        let current = || reviews.iter().filter(|r| !r.dismissed && !r.stale);
+        Ok(if current().any(|r| r.state == "REQUEST_CHANGES") {
+            Some(Review::ChangesRequested)
+        } else if current().any(|r| r.state == "APPROVED") {
+            Some(Review::Approved)
+        } else if current().any(|r| r.state == "REQUEST_REVIEW") {
+            Some(Review::Required)
+        } else {
+            None
+        })
+    }
+
+    /// The combined commit status of `sha`, `None` when no CI reported on it
+    fn checks(&self, sha: &str) -> Result<Option<Checks>, ApiError> {
+        let status: CombinedStatus = self
+            .client
+            .get(&format!("{}/commits/{sha}/status", self.repo_path()))?;
+
+        if status.total_count == 0 {
+            return Ok(None);
+        }
+        Ok(Some(match status.state.as_str() {
+            "success" => Checks::Passing,
+            "failure" | "error" => Checks::Failing,
+            _ => Checks::Pending,
+        }))
+    }
+
+    fn stack_comment(&self, number: u64) -> Result<Option<Comment>, ApiError> {
+        let comments: Vec<IssueComment> = self
+            .client
+            .get(&format!("{}/issues/{number}/comments", self.repo_path()))?;
+
+        // If multiple stack comments exist, take the first one
+        Ok(comments
+            .into_iter()
+            .find(|c| c.body.contains(STACK_COMMENT_HEADING))
+            .map(|c| Comment {
+                id: c.id.to_string(),
+                body: c.body,
+            }))
+    }
+}
+
+impl Forge for Gitea {
+    fn name(&self) -> &'static str {
+        "Gitea"
+    }
+
+    fn default_branch(&self) -> Result<String> {
+        let repository: RepositoryInfo = self
+            .client
+            .get(&self.repo_path())
+            .context("Failed to look up the repository")?;
+        Ok(repository.default_branch)
+    }
+
+    fn fetch_prs(&self, branches: &[&str]) -> Result<PrCache> {
+        let mut cache = PrCache::default();
+        if branches.is_empty() {
+            return Ok(cache);
+        }
+
+        // There is no lookup by head branch, so list the open PRs once and match them up.
+        // Forks can have branches of the same name, so the head owner has to match too.
+        let pull_requests = self
+            .open_pull_requests()
+            .context("Failed to list the open PRs")?;
+
+        for branch in branches {
+            let Some(pr) = pull_requests
+                .iter()
+                .find(|pr| pr.head.name == *branch && self.is_head_owner(pr))
+            else {
+                continue;
+            };
+
+            let stack_comment = self
+                .stack_comment(pr.number)
+                .with_context(|| format!("Failed to read the comments of {}", pr.html_url))?;
+
+            cache.insert(
+                branch,
+                PrInfo {
+                    number: pr.number,
+                    url: pr.html_url.clone(),
+                    title: strip_draft(&pr.title).to_string(),
+                    body: pr.body.clone().unwrap_or_default(),
+                    base: pr.base.name.clone(),
+                    is_draft: pr.draft || strip_draft(&pr.title) != pr.title,
+                    stack_comment,
+                },
+            );
+        }
+        Ok(cache)
+    }
+
+    fn create_pr(
+        &self,
+        head_branch: &str,
+        base_branch: &str,
+        title: &str,
+        body: &str,
+        draft: bool,
+    ) -> Result<String> {
+        let title = if draft {
+            format!("{DRAFT_PREFIX}{title}")
+        } else {
+            title.to_string()
+        };
+        let head = match &self.head_owner {
+            Some(owner) => format!("{owner}:{head_branch}"),
+            None => head_branch.to_string(),
+        };
+
+        let pr: PullRequest = self
+            .client
+            .send(
+                "POST",
+                &format!("{}/pulls", self.repo_path()),
+                &NewPullRequest {
+                    head: &head,
+                    base: base_branch,
+                    title: &title,
+                    body,
+                },
+            )
+            .with_context(|| format!("Failed to create PR for {head_branch}"))?;
+
+        println!("PR created: {}", pr.html_url);
+        Ok(pr.html_url)
+    }
+
+    fn add_pr_comment(&self, pr_url: &str, comment: &str) -> Result<String> {
+        let path = format!(
+            "{}/issues/{}/comments",
+            self.repo_path(),
+            pr_number(pr_url)?
+        );
+        let comment: IssueComment = self
+            .client
+            .send("POST", &path, &json!({ "body": comment }))
+            .with_context(|| format!("Failed to comment on PR {pr_url}"))?;
+        Ok(comment.id.to_string())
+    }
+
+    fn update_pr_comment(&self, pr_url: &str, comment_id: &str, comment: &str) -> Result<()> {
+        let path = format!("{}/issues/comments/{comment_id}", self.repo_path());
+        let _: serde_json::Value = self
+            .client
+            .send("PATCH", &path, &json!({ "body": comment }))
+            .with_context(|| format!("Failed to update comment {comment_id} on PR {pr_url}"))?;
+        Ok(())
+    }
+
+    fn delete_pr_comment(&self, pr_url: &str, comment_id: &str) -> Result<()> {
+        let path = format!("{}/issues/comments/{comment_id}", self.repo_path());
+        self.client
+            .delete(&path)
+            .with_context(|| format!("Failed to delete comment {comment_id} on PR {pr_url}"))
+    }
+
+    fn edit_pr_base(&self, pr_url: &str, base_branch: &str) -> Result<()> {
+        self.edit_pull_request(pr_url, json!({ "base": base_branch }))
+    }
+
+    fn set_pr_draft(&self, pr_url: &str, draft: bool) -> Result<()> {
+        let pr = self.pull_request(pr_url)?;
+        let title = strip_draft(&pr.title);
+        let title = if draft {
+            format!("{DRAFT_PREFIX}{title}")
+        } else {
+            title.to_string()
+        };
+        self.edit_pull_request(pr_url, json!({ "title": title }))
+    }
+
+    fn edit_pr_title(&self, pr_url: &str, title: &str) -> Result<()> {
+        // Keep the WIP prefix, replacing it would mark the PR as ready
+        let pr = self.pull_request(pr_url)?;
+        let title = if strip_draft(&pr.title) != pr.title {
+            format!("{DRAFT_PREFIX}{title}")
+        } else {
+            title.to_string()
+        };
+        self.edit_pull_request(pr_url, json!({ "title": title }))
+    }
+
+    fn edit_pr_body(&self, pr_url: &str, body: &str) -> Result<()> {
+        self.edit_pull_request(pr_url, json!({ "body": body }))
+    }
+
+    fn close_pr(&self, pr_url: &str) -> Result<()> {
+        self.edit_pull_request(pr_url, json!({ "state": "closed" }))
+    }
+
+    fn fetch_pr_statuses(&self, branches: &[&str]) -> Result<HashMap<String, PrStatus>> {
+        let mut statuses = HashMap::new();
+        if branches.is_empty() {
+            return Ok(statuses);
+        }
+
+        // Closed PRs pile up, so walk the list newest first and stop once every branch has one
+        let mut found: HashMap<&str, PullRequest> = HashMap::new();
+        for page in 1.. {
+            let batch = self
+                .pull_requests_page("all", page)
+                .context("Failed to list the PRs")?;
+            let done = batch.len() < PAGE_SIZE;
+            for pr in batch {
+                if let Some(branch) = branches.iter().find(|b| **b == pr.head.name) {
+                    if self.is_head_owner(&pr) && !found.contains_key(branch) {
+                        found.insert(branch, pr);
+                    }
+                }
+            }
+            if done || found.len() == branches.len() {
+                break;
+            }
+        }
+
+        for (branch, pr) in found {
+            let review = self
+                .review(pr.number)
+                .with_context(|| format!("Failed to read the reviews of {}", pr.html_url))?;
+            let checks = self
+                .checks(&pr.head.sha)
+                .with_context(|| format!("Failed to read the checks of {}", pr.html_url))?;
+
+            let state = if pr.merged {
+                PrState::Merged
+            } else if pr.state == "closed" {
+                PrState::Closed
+            } else if pr.draft || strip_draft(&pr.title) != pr.title {
+                PrState::Draft
+            } else {
+                PrState::Open
+            };
+
+            statuses.insert(
+                branch.to_string(),
+                PrStatus {
+                    number: pr.number,
+                    url: pr.html_url,
+                    state,
+                    review,
+                    checks,
+                },
+            );
+        }
+        Ok(statuses)
+    }
+}
+
+/// Remove the default work in progress prefixes of Gitea and Forgejo
+fn strip_draft(title: &str) -> &str {
+    ["WIP:", "[WIP]"]
+        .iter()
+        .find_map(|prefix| title.strip_prefix(prefix))
+        .map_or(title, str::trim_start)
+}
+
+fn tea_config_file() -> Option<PathBuf> {
+    let config_dir = if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?).join(".config")
+    };
+    Some(config_dir.join("tea").join("config.yml"))
+}
+
+/// Read the `token` of the login for a host from tea's `config.yml`, which looks like
+///
+/// ```yaml
+/// logins:
+///     - name: codeberg.org
+///       url: https://codeberg.org
+///       token: 0123abcd...
+/// ```
+fn config_file_token(contents: &str, host: &str) -> Option<String> {
+    let mut url_matches = false;
+    let mut token = None;
+    for line in contents.lines() {
+        let mut line = line.trim();
+        if let Some(rest) = line.strip_prefix("- ") {
+            // A new login starts
+            if url_matches && token.is_some() {
+                return token;
+            }
+            url_matches = false;
+            token = None;
+            line = rest.trim();
+        }
+
+        let value = |key: &str| {
+            line.strip_prefix(key)
+                .map(|v| v.trim().trim_matches(['"', '\'']).to_string())
+        };
+        if let Some(url) = value("url:") {
+            let url_host = url.split_once("://").map_or(url.as_str(), |(_, h)| h);
+            url_matches = url_host.trim_end_matches('/') == host;
+        } else if let Some(value) = value("token:") {
+            token = (!value.is_empty()).then_some(value);
+        }
+    }
+    if url_matches {
+        token
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forge::ForgeKind;
+    use crate::http::test_server;
+
+    #[test]
+    fn config_file_token_reads_the_login_for_the_host() {
+        let contents = "logins:\n    - name: codeberg.org\n      url: https://codeberg.org\n      token: \"codeberg-token\"\n      default: true\n    - name: work\n      url: https://gitea.example.com/\n      token: work-token\n    - name: empty\n      url: https://forgejo.example.com\n      token: \"\"\npreferences:\n    editor: false\n";
+        assert_eq!(
+            config_file_token(contents, "codeberg.org").as_deref(),
+            Some("codeberg-token")
+        );
+        assert_eq!(
+            config_file_token(contents, "gitea.example.com").as_deref(),
+            Some("work-token")
+        );
+        assert_eq!(config_file_token(contents, "forgejo.example.com"), None);
+        assert_eq!(config_file_token(contents, "example.com"), None);
+    }
+
+    #[test]
+    fn strip_draft_removes_wip_prefixes() {
+        assert_eq!(strip_draft("WIP: Add login"), "Add login");
+        assert_eq!(strip_draft("WIP:Add login"), "Add login");
+        assert_eq!(strip_draft("[WIP] Add login"), "Add login");
+        assert_eq!(strip_draft("Add WIP: login"), "Add WIP: login");
+        assert_eq!(strip_draft("Add login"), "Add login");
+    }
+
+    #[test]
+    fn detects_gitea_and_forgejo_hosts() {
+        for host in [
+            "codeberg.org",
+            "gitea.example.com",
+            "code.gitea.io",
+            "forgejo.example.com:2222",
+        ] {
+            assert_eq!(ForgeKind::detect(host), ForgeKind::Gitea, "{host}");
+        }
+        assert_eq!(ForgeKind::detect("git.example.com"), ForgeKind::GitHub);
+        assert_eq!(ForgeKind::detect("mygitea.example.com"), ForgeKind::GitHub);
+    }
+
+    fn gitea(url: &str) -> Gitea {
+        Gitea {
+            client: Client::new(url, vec![]),
+            owner: "up".to_string(),
+            repo: "demo".to_string(),
+            head_owner: Some("octo".to_string()),
+        }
+    }
+
+    #[test]
+    fn fetch_prs_keeps_the_prs_from_the_fork() {
+        // Responses of the Gitea API, trimmed to the fields stack-prs reads
+        let (url, server) = test_server::serve(vec![
+            (
+                200,
+                r#"[
+                    {"number":12,"html_url":"https://codeberg.org/up/demo/pulls/12","title":"Same branch upstream","body":"","base":{"ref":"main","sha":"b0","repo":{"owner":{"login":"up"}}},"head":{"ref":"feat-a","sha":"a0","repo":{"owner":{"login":"up"}}},"draft":false,"state":"open","merged":false},
+                    {"number":11,"html_url":"https://codeberg.org/up/demo/pulls/11","title":"WIP: First","body":null,"base":{"ref":"main","sha":"b0","repo":{"owner":{"login":"up"}}},"head":{"ref":"feat-a","sha":"a1","repo":{"owner":{"login":"Octo"}}},"draft":false,"state":"open","merged":false},
+                    {"number":10,"html_url":"https://codeberg.org/up/demo/pulls/10","title":"Second","body":"Body","base":{"ref":"feat-a","sha":"a1","repo":{"owner":{"login":"up"}}},"head":{"ref":"feat-b","sha":"b1","repo":{"owner":{"login":"someone"}}},"draft":false,"state":"open","merged":false}
+                ]"#,
+            ),
+            (
+                200,
+                r###"[{"id":5,"body":"Looks good"},{"id":6,"body":"## Stack Information\n\n* #11"}]"###,
+            ),
+        ]);
+
+        let prs = gitea(&url).fetch_prs(&["feat-a", "feat-b"]).unwrap();
+        let pr = prs.get("feat-a").unwrap();
+        assert_eq!(pr.number, 11);
+        assert_eq!(pr.title, "First");
+        assert!(pr.is_draft);
+        assert_eq!(pr.body, "");
+        assert_eq!(pr.stack_comment.as_ref().unwrap().id, "6");
+        assert!(prs.get("feat-b").is_none());
+
+        let paths: Vec<_> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/repos/up/demo/pulls?state=open&sort=newest&limit=50&page=1",
+                "/repos/up/demo/issues/11/comments",
+            ]
+        );
+    }
+
+    #[test]
+    fn fetch_pr_statuses_takes_the_newest_pr_from_the_fork() {
+        let (url, server) = test_server::serve(vec![
+            (
+                200,
+                r#"[
+                    {"number":12,"html_url":"https://codeberg.org/up/demo/pulls/12","title":"Same branch upstream","base":{"ref":"main"},"head":{"ref":"feat-a","sha":"a0","repo":{"owner":{"login":"up"}}},"state":"open"},
+                    {"number":11,"html_url":"https://codeberg.org/up/demo/pulls/11","title":"First","base":{"ref":"main"},"head":{"ref":"feat-a","sha":"a1","repo":{"owner":{"login":"octo"}}},"state":"closed","merged":true},
+                    {"number":9,"html_url":"https://codeberg.org/up/demo/pulls/9","title":"First, first try","base":{"ref":"main"},"head":{"ref":"feat-a","sha":"a2","repo":{"owner":{"login":"octo"}}},"state":"closed"}
+                ]"#,
+            ),
+            (
+                200,
+                r#"[{"state":"REQUEST_CHANGES","dismissed":true},{"state":"APPROVED"}]"#,
+            ),
+            (200, r#"{"state":"failure","total_count":2}"#),
+        ]);
+
+        let statuses = gitea(&url).fetch_pr_statuses(&["feat-a"]).unwrap();
+        let status = &statuses["feat-a"];
+        assert_eq!(status.number, 11);
+        assert_eq!(status.state, PrState::Merged);
+        assert_eq!(status.review, Some(Review::Approved));
+        assert_eq!(status.checks, Some(Checks::Failing));
+
+        let paths: Vec<_> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/repos/up/demo/pulls?state=all&sort=newest&limit=50&page=1",
+                "/repos/up/demo/pulls/11/reviews",
+                "/repos/up/demo/commits/a1/status",
+            ]
+        );
+    }
+}
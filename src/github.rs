@@ -1,215 +1,727 @@
 use anyhow::{Context, Result};
-use std::process::Command;
 use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::forge::{
+    Checks, Comment, Forge, PrCache, PrInfo, PrState, PrStatus, Review, STACK_COMMENT_HEADING,
+};
+use crate::remote::Repository;
+
+mod native;
+
+/// Which implementation stack-prs uses to talk to GitHub
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Shell out to the GitHub CLI
+    Gh,
+    /// Call the REST and GraphQL APIs directly
+    Native,
+}
 
-/// Check if a PR exists for a given branch
-pub fn pr_exists(branch: &str) -> Result<bool> {
-    debug!(
-        "Executing command: gh pr list --head {} --json number",
-        branch
-    );
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gh" => Ok(BackendKind::Gh),
+            "native" => Ok(BackendKind::Native),
+            _ => Err(format!(
+                "unknown GitHub backend '{s}', expected one of: gh, native"
+            )),
+        }
+    }
+}
 
-    let output = Command::new("gh")
-        .arg("pr")
-        .arg("list")
-        .arg("--head")
-        .arg(branch)
-        .arg("--json")
-        .arg("number")
-        .output()
-        .context("Failed to execute gh pr list. Make sure GitHub CLI (gh) is installed and authenticated.")?;
+/// Create the backend of the given kind for PRs in `repository`, whose branches live in
+/// `head_repository` when that is a fork. `api_url` only applies to the native backend.
+pub fn backend(
+    kind: BackendKind,
+    repository: Repository,
+    head_repository: Option<Repository>,
+    api_url: &str,
+) -> Result<Box<dyn Forge>> {
+    let head_owner = head_repository.map(|r| r.owner);
+    match kind {
+        BackendKind::Gh => Ok(Box::new(GhCli {
+            repository,
+            head_owner,
+        })),
+        BackendKind::Native => Ok(Box::new(native::NativeClient::new(
+            &repository,
+            head_owner,
+            api_url,
+        )?)),
+    }
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("gh pr list failed: {stderr}");
+/// The API URL of a GitHub host: api.github.com for github.com, `/api/v3` on GitHub Enterprise Server
+pub fn api_url(host: &str) -> String {
+    if host == "github.com" {
+        "https://api.github.com".to_string()
+    } else {
+        format!("https://{host}/api/v3")
     }
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    // If the output is "[]", no PRs exist
-    Ok(!stdout.trim().is_empty() && stdout.trim() != "[]")
+/// A GraphQL response, which can carry `errors` next to or instead of its `data`
+#[derive(Deserialize)]
+struct GraphQlResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphQlError>,
 }
 
-/// Create a pull request using the GitHub CLI (gh) and return the PR URL
-pub fn create_pr(head_branch: &str, base_branch: &str, title: &str) -> Result<String> {
-    debug!(
-        "Executing command: gh pr create --head {} --base {} --title {} --body \"\"",
-        head_branch, base_branch, title
-    );
+#[derive(Deserialize)]
+struct GraphQlError {
+    message: String,
+}
 
-    let output = Command::new("gh")
-        .arg("pr")
-        .arg("create")
-        .arg("--head")
-        .arg(head_branch)
-        .arg("--base")
-        .arg(base_branch)
-        .arg("--title")
-        .arg(title)
-        .arg("--body")
-        .arg("") // Empty body for now
-        .output()
-        .context("Failed to execute gh pr create. Make sure GitHub CLI (gh) is installed and authenticated.")?;
+/// Aliased `pullRequests` connections of one repository, see `branches_query`
+#[derive(Deserialize)]
+struct GraphQlData<T> {
+    repository: HashMap<String, Connection<T>>,
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("gh pr create failed: {stderr}");
-    }
+#[derive(Deserialize)]
+struct Connection<T> {
+    nodes: Vec<T>,
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let pr_url = stdout.trim().to_string();
-    println!("PR created: {}", pr_url);
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlPr {
+    number: u64,
+    url: String,
+    title: String,
+    body: String,
+    base_ref_name: String,
+    is_draft: bool,
+    head_repository_owner: Option<GraphQlOwner>,
+    comments: GraphQlComments,
+}
 
-    Ok(pr_url)
+/// One page of a PR's comments
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlComments {
+    nodes: Vec<GraphQlComment>,
+    page_info: GraphQlPageInfo,
 }
 
-/// Add a comment to a PR using the PR URL
-pub fn add_pr_comment(pr_url: &str, comment: &str) -> Result<()> {
-    debug!(
-        "Executing command: gh pr comment {} --body \"{}\"",
-        pr_url, comment
-    );
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlPageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
 
-    let output = Command::new("gh")
-        .arg("pr")
-        .arg("comment")
-        .arg(pr_url)
-        .arg("--body")
-        .arg(comment)
-        .output()
-        .context("Failed to execute gh pr comment")?;
+/// Response to `comments_query`
+#[derive(Deserialize)]
+struct GraphQlCommentsData {
+    repository: GraphQlCommentsRepository,
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("gh pr comment failed: {stderr}");
-    }
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlCommentsRepository {
+    pull_request: GraphQlCommentsPr,
+}
 
-    Ok(())
+#[derive(Deserialize)]
+struct GraphQlCommentsPr {
+    comments: GraphQlComments,
 }
 
-/// Get the PR URL and title for an existing PR by branch name
-pub fn get_pr_info(branch: &str) -> Result<(String, String)> {
-    debug!(
-        "Executing command: gh pr list --head {} --json url,title",
-        branch
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlPrStatus {
+    number: u64,
+    url: String,
+    state: String,
+    is_draft: bool,
+    review_decision: Option<String>,
+    head_repository_owner: Option<GraphQlOwner>,
+    commits: Connection<GraphQlCommitNode>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlCommitNode {
+    commit: GraphQlCommit,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlCommit {
+    status_check_rollup: Option<GraphQlRollup>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlRollup {
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct GraphQlOwner {
+    login: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphQlComment {
+    database_id: u64,
+    body: String,
+}
+
+/// Build a query that looks up the PRs of every branch, using one aliased `pullRequests`
+/// field per branch with the given arguments, selecting the fields of `fragment pr`.
+/// Expects `$owner` and `$name` variables.
+fn branches_query(branches: &[&str], arguments: &str, fragment: &str) -> Result<String> {
+    let mut query = String::from(
+        "query($owner: String!, $name: String!) {\n  repository(owner: $owner, name: $name) {\n",
     );
+    for (index, branch) in branches.iter().enumerate() {
+        // JSON string literals are valid GraphQL strings
+        query.push_str(&format!(
+            "    pr{index}: pullRequests(headRefName: {}, {arguments}) {{ nodes {{ ...pr }} }}\n",
+            serde_json::to_string(branch)?
+        ));
+    }
+    query.push_str("  }\n}\n");
+    query.push_str(fragment);
+    Ok(query)
+}
 
-    let output = Command::new("gh")
-        .arg("pr")
-        .arg("list")
-        .arg("--head")
-        .arg(branch)
-        .arg("--json")
-        .arg("url,title")
-        .output()
-        .context("Failed to execute gh pr list")?;
+/// Query for the open PRs of every branch.
+/// Forks can have branches of the same name, so `pr_cache` picks the right PR by its head owner.
+fn pr_query(branches: &[&str]) -> Result<String> {
+    branches_query(
+        branches,
+        "states: OPEN, first: 20",
+        "fragment pr on PullRequest {\n  number url title body baseRefName isDraft headRepositoryOwner { login }\n  comments(first: 100) { nodes { databaseId body } pageInfo { hasNextPage endCursor } }\n}\n",
+    )
+}
+
+/// Query for the comments of PR `number` after `cursor`, for PRs with more comments
+/// than the first page of `pr_query`. Expects `$owner` and `$name` variables.
+fn comments_query(number: u64, cursor: &str) -> Result<String> {
+    Ok(format!(
+        "query($owner: String!, $name: String!) {{\n  repository(owner: $owner, name: $name) {{\n    pullRequest(number: {number}) {{\n      comments(first: 100, after: {}) {{ nodes {{ databaseId body }} pageInfo {{ hasNextPage endCursor }} }}\n    }}\n  }}\n}}\n",
+        serde_json::to_string(cursor)?
+    ))
+}
+
+/// Query for the newest PRs of every branch in any state, with review and CI status
+fn status_query(branches: &[&str]) -> Result<String> {
+    branches_query(
+        branches,
+        "first: 20, orderBy: {field: CREATED_AT, direction: DESC}",
+        "fragment pr on PullRequest {\n  number url state isDraft reviewDecision headRepositoryOwner { login }\n  commits(last: 1) { nodes { commit { statusCheckRollup { state } } } }\n}\n",
+    )
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("gh pr list failed: {stderr}");
+/// Turn the response to `status_query` into the newest PR of each branch whose head
+/// lives in a repository owned by `head_owner`
+fn pr_statuses(
+    branches: &[&str],
+    data: GraphQlData<GraphQlPrStatus>,
+    head_owner: &str,
+) -> HashMap<String, PrStatus> {
+    let mut repository = data.repository;
+    let mut statuses = HashMap::new();
+    for (index, branch) in branches.iter().enumerate() {
+        let Some(pr) = repository
+            .remove(&format!("pr{index}"))
+            .and_then(|connection| {
+                connection.nodes.into_iter().find(|pr| {
+                    pr.head_repository_owner
+                        .as_ref()
+                        .is_some_and(|owner| owner.login.eq_ignore_ascii_case(head_owner))
+                })
+            })
+        else {
+            continue;
+        };
+
+        let state = match pr.state.as_str() {
+            "MERGED" => PrState::Merged,
+            "CLOSED" => PrState::Closed,
+            _ if pr.is_draft => PrState::Draft,
+            _ => PrState::Open,
+        };
+        let review = match pr.review_decision.as_deref() {
+            Some("APPROVED") => Some(Review::Approved),
+            Some("CHANGES_REQUESTED") => Some(Review::ChangesRequested),
+            Some("REVIEW_REQUIRED") => Some(Review::Required),
+            _ => None,
+        };
+        let checks = pr
+            .commits
+            .nodes
+            .first()
+            .and_then(|node| node.commit.status_check_rollup.as_ref())
+            .map(|rollup| match rollup.state.as_str() {
+                "SUCCESS" => Checks::Passing,
+                "FAILURE" | "ERROR" => Checks::Failing,
+                _ => Checks::Pending,
+            });
+
+        statuses.insert(
+            branch.to_string(),
+            PrStatus {
+                number: pr.number,
+                url: pr.url,
+                state,
+                review,
+                checks,
+            },
+        );
     }
+    statuses
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+/// Find the stack comment of `pr`, running `comments_query` through `graphql` for the
+/// following pages while the ones seen so far do not have it.
+/// If multiple stack comments exist, take the first one.
+fn stack_comment(
+    pr: &GraphQlPr,
+    graphql: &mut impl FnMut(&str) -> Result<GraphQlCommentsData>,
+) -> Result<Option<Comment>> {
+    let mut page = None;
+    loop {
+        let comments = page.as_ref().unwrap_or(&pr.comments);
+        if let Some(comment) = comments
+            .nodes
+            .iter()
+            .find(|c| c.body.contains(STACK_COMMENT_HEADING))
+        {
+            return Ok(Some(Comment {
+                id: comment.database_id.to_string(),
+                body: comment.body.clone(),
+            }));
+        }
+
+        let cursor = match &comments.page_info {
+            GraphQlPageInfo {
+                has_next_page: true,
+                end_cursor: Some(cursor),
+            } => cursor,
+            _ => return Ok(None),
+        };
+        let data = graphql(&comments_query(pr.number, cursor)?)
+            .with_context(|| format!("Failed to fetch the comments of PR #{}", pr.number))?;
+        page = Some(data.repository.pull_request.comments);
+    }
+}
 
-    // Parse JSON to extract url and title
-    let json: serde_json::Value = serde_json::from_str(&stdout)
-        .context("Failed to parse gh pr list JSON output")?;
+/// Turn the response to `pr_query` into a cache keyed by branch, keeping only PRs
+/// whose head lives in a repository owned by `head_owner`.
+/// `graphql` runs the queries for PRs whose stack comment is not on the first page.
+fn pr_cache(
+    branches: &[&str],
+    data: &GraphQlData<GraphQlPr>,
+    head_owner: &str,
+    mut graphql: impl FnMut(&str) -> Result<GraphQlCommentsData>,
+) -> Result<PrCache> {
+    let mut cache = PrCache::default();
+    for (index, branch) in branches.iter().enumerate() {
+        let Some(pr) = data
+            .repository
+            .get(&format!("pr{index}"))
+            .and_then(|connection| {
+                connection.nodes.iter().find(|pr| {
+                    pr.head_repository_owner
+                        .as_ref()
+                        .is_some_and(|owner| owner.login.eq_ignore_ascii_case(head_owner))
+                })
+            })
+        else {
+            continue;
+        };
+
+        let stack_comment = stack_comment(pr, &mut graphql)?;
+
+        cache.insert(
+            branch,
+            PrInfo {
+                number: pr.number,
+                url: pr.url.clone(),
+                title: pr.title.clone(),
+                body: pr.body.clone(),
+                base: pr.base_ref_name.clone(),
+                is_draft: pr.is_draft,
+                stack_comment,
+            },
+        );
+    }
+    Ok(cache)
+}
 
-    let pr_array = json.as_array()
-        .context("Expected JSON array from gh pr list")?;
+/// Talks to GitHub through the GitHub CLI, which must be installed and authenticated
+/// for the repository's host
+pub struct GhCli {
+    repository: Repository,
+    /// Owner of the fork the branches are pushed to, if not `repository`
+    head_owner: Option<String>,
+}
 
-    if pr_array.is_empty() {
-        anyhow::bail!("No PR found for branch {}", branch);
+impl GhCli {
+    fn head_owner(&self) -> &str {
+        self.head_owner.as_deref().unwrap_or(&self.repository.owner)
     }
 
-    let pr_obj = &pr_array[0];
-    let pr_url = pr_obj["url"].as_str()
-        .context("Missing 'url' field in PR JSON")?
-        .to_string();
-    let pr_title = pr_obj["title"].as_str()
-        .context("Missing 'title' field in PR JSON")?
-        .to_string();
+    /// Run a GraphQL query against the repository and decode its `data`.
+    /// Owner and name are passed as raw strings, `-F` would turn a repository named
+    /// `123` or `true` into a number or boolean.
+    fn graphql<T: DeserializeOwned>(&self, query: &str) -> Result<T> {
+        debug!(
+            "Executing command: gh api graphql --hostname {} -f owner={} -f name={} -f query={query}",
+            self.repository.host, self.repository.owner, self.repository.name
+        );
 
-    Ok((pr_url, pr_title))
+        let output = Command::new("gh")
+        .arg("api")
+        .arg("graphql")
+        .arg("--hostname")
+        .arg(&self.repository.host)
+        .arg("-f")
+        .arg(format!("owner={}", self.repository.owner))
+        .arg("-f")
+        .arg(format!("name={}", self.repository.name))
+        .arg("-f")
+        .arg(format!("query={query}"))
+        .output()
+        .context("Failed to execute gh api graphql. Make sure GitHub CLI (gh) is installed and authenticated.")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh api graphql failed: {stderr}");
+        }
+
+        let response: GraphQlResponse<T> = serde_json::from_slice(&output.stdout)
+            .context("Failed to parse gh api graphql JSON output")?;
+        if !response.errors.is_empty() {
+            let messages: Vec<_> = response.errors.into_iter().map(|e| e.message).collect();
+            anyhow::bail!("gh api graphql failed: {}", messages.join("; "));
+        }
+        response
+            .data
+            .context("gh api graphql returned a response without data")
+    }
 }
 
-/// Check if a stack comment already exists on a PR and return its ID if found
-fn get_stack_comment_id(pr_url: &str) -> Result<Option<String>> {
-    debug!(
-        "Executing command: gh pr view {} --json comments --jq '.comments[] | select(.body | contains(\"## Stack Information\")) | .id'",
-        pr_url
-    );
+/// The head of a PR: the branch itself, or `owner:branch` for a branch in a fork
+fn pr_head(head_owner: Option<&str>, branch: &str) -> String {
+    match head_owner {
+        Some(owner) => format!("{owner}:{branch}"),
+        None => branch.to_string(),
+    }
+}
 
-    let output = Command::new("gh")
-        .arg("pr")
+impl Forge for GhCli {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn default_branch(&self) -> Result<String> {
+        debug!(
+            "Executing command: gh repo view {} --json defaultBranchRef --jq .defaultBranchRef.name",
+            self.repository
+        );
+
+        let output = Command::new("gh")
+        .arg("repo")
         .arg("view")
-        .arg(pr_url)
+        .arg(self.repository.to_string())
         .arg("--json")
-        .arg("comments")
+        .arg("defaultBranchRef")
         .arg("--jq")
-        .arg(".comments[] | select(.body | contains(\"## Stack Information\")) | .id")
+        .arg(".defaultBranchRef.name")
         .output()
-        .context("Failed to execute gh pr view")?;
+        .context("Failed to execute gh repo view. Make sure GitHub CLI (gh) is installed and authenticated.")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh repo view failed: {stderr}");
+        }
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("gh pr view failed: {stderr}");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let branch = stdout.trim();
+        if branch.is_empty() {
+            anyhow::bail!("gh repo view did not report a default branch");
+        }
+
+        Ok(branch.to_string())
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let comment_id = stdout.trim();
+    fn create_pr(
+        &self,
+        head_branch: &str,
+        base_branch: &str,
+        title: &str,
+        body: &str,
+        draft: bool,
+    ) -> Result<String> {
+        let head = pr_head(self.head_owner.as_deref(), head_branch);
+
+        debug!(
+            "Executing command: gh pr create --repo {} --head {} --base {} --title {} --body ...{}",
+            self.repository,
+            head,
+            base_branch,
+            title,
+            if draft { " --draft" } else { "" }
+        );
+
+        let mut command = Command::new("gh");
+        command
+            .arg("pr")
+            .arg("create")
+            .arg("--repo")
+            .arg(self.repository.to_string())
+            .arg("--head")
+            .arg(&head)
+            .arg("--base")
+            .arg(base_branch)
+            .arg("--title")
+            .arg(title)
+            .arg("--body")
+            .arg(body);
+        if draft {
+            command.arg("--draft");
+        }
+
+        let output = command.output().context(
+        "Failed to execute gh pr create. Make sure GitHub CLI (gh) is installed and authenticated.",
+    )?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr create failed: {stderr}");
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let pr_url = stdout.trim().to_string();
+        println!("PR created: {}", pr_url);
+
+        Ok(pr_url)
+    }
 
-    if comment_id.is_empty() {
-        Ok(None)
-    } else {
-        // If multiple comments exist, take the first one
-        Ok(Some(comment_id.lines().next().unwrap_or("").to_string()))
+    fn add_pr_comment(&self, pr_url: &str, comment: &str) -> Result<String> {
+        debug!(
+            "Executing command: gh pr comment {} --body \"{}\"",
+            pr_url, comment
+        );
+
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("comment")
+            .arg(pr_url)
+            .arg("--body")
+            .arg(comment)
+            .output()
+            .context("Failed to execute gh pr comment")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr comment failed: {stderr}");
+        }
+
+        // gh prints the URL of the new comment, like https://github.com/o/r/pull/1#issuecomment-123
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout
+            .trim()
+            .rsplit_once("#issuecomment-")
+            .map(|(_, id)| id.to_string())
+            .with_context(|| format!("Failed to find the comment id in gh output: {stdout}"))
     }
-}
 
-/// Update an existing comment on a PR
-fn update_pr_comment(pr_url: &str, comment_id: &str, comment: &str) -> Result<()> {
-    debug!(
-        "Executing command: gh api -X PATCH /repos/{{owner}}/{{repo}}/issues/comments/{} -f body=...",
-        comment_id
-    );
+    fn fetch_prs(&self, branches: &[&str]) -> Result<PrCache> {
+        if branches.is_empty() {
+            return Ok(PrCache::default());
+        }
 
-    // Extract owner/repo from PR URL
-    // PR URL format: https://github.com/owner/repo/pull/123
-    let parts: Vec<&str> = pr_url.trim_end_matches('/').split('/').collect();
-    if parts.len() < 5 {
-        anyhow::bail!("Invalid PR URL format: {}", pr_url);
+        let data = self.graphql(&pr_query(branches)?)?;
+        pr_cache(branches, &data, self.head_owner(), |query| {
+            self.graphql(query)
+        })
     }
-    let owner = parts[parts.len() - 4];
-    let repo = parts[parts.len() - 3];
 
-    let api_endpoint = format!("/repos/{}/{}/issues/comments/{}", owner, repo, comment_id);
+    fn edit_pr_base(&self, pr_url: &str, base_branch: &str) -> Result<()> {
+        debug!(
+            "Executing command: gh pr edit {} --base {}",
+            pr_url, base_branch
+        );
+
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("edit")
+            .arg(pr_url)
+            .arg("--base")
+            .arg(base_branch)
+            .output()
+            .context("Failed to execute gh pr edit")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr edit failed: {stderr}");
+        }
+
+        Ok(())
+    }
 
-    let output = Command::new("gh")
-        .arg("api")
-        .arg("-X")
-        .arg("PATCH")
-        .arg(&api_endpoint)
-        .arg("-f")
-        .arg(format!("body={}", comment))
-        .output()
-        .context("Failed to execute gh api")?;
+    fn set_pr_draft(&self, pr_url: &str, draft: bool) -> Result<()> {
+        debug!(
+            "Executing command: gh pr ready {}{}",
+            pr_url,
+            if draft { " --undo" } else { "" }
+        );
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("gh api failed: {stderr}");
+        let mut command = Command::new("gh");
+        command.arg("pr").arg("ready").arg(pr_url);
+        if draft {
+            command.arg("--undo");
+        }
+
+        let output = command.output().context("Failed to execute gh pr ready")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr ready failed: {stderr}");
+        }
+
+        Ok(())
     }
 
-    Ok(())
-}
+    fn edit_pr_title(&self, pr_url: &str, title: &str) -> Result<()> {
+        debug!("Executing command: gh pr edit {} --title {}", pr_url, title);
 
-/// Add or update a stack comment on a PR
-pub fn add_or_update_stack_comment(pr_url: &str, comment: &str) -> Result<()> {
-    if let Some(comment_id) = get_stack_comment_id(pr_url)? {
-        debug!("Updating existing stack comment {} on PR {}", comment_id, pr_url);
-        update_pr_comment(pr_url, &comment_id, comment)?;
-    } else {
-        debug!("Adding new stack comment to PR {}", pr_url);
-        add_pr_comment(pr_url, comment)?;
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("edit")
+            .arg(pr_url)
+            .arg("--title")
+            .arg(title)
+            .output()
+            .context("Failed to execute gh pr edit")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr edit failed: {stderr}");
+        }
+
+        Ok(())
+    }
+
+    fn edit_pr_body(&self, pr_url: &str, body: &str) -> Result<()> {
+        debug!("Executing command: gh pr edit {} --body ...", pr_url);
+
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("edit")
+            .arg(pr_url)
+            .arg("--body")
+            .arg(body)
+            .output()
+            .context("Failed to execute gh pr edit")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr edit failed: {stderr}");
+        }
+
+        Ok(())
+    }
+
+    fn update_pr_comment(&self, pr_url: &str, comment_id: &str, comment: &str) -> Result<()> {
+        let api_endpoint = format!(
+            "/repos/{}/issues/comments/{}",
+            self.repository.slug(),
+            comment_id
+        );
+
+        debug!(
+            "Executing command: gh api --hostname {} -X PATCH {} -f body=...",
+            self.repository.host, api_endpoint
+        );
+
+        let output = Command::new("gh")
+            .arg("api")
+            .arg("--hostname")
+            .arg(&self.repository.host)
+            .arg("-X")
+            .arg("PATCH")
+            .arg(&api_endpoint)
+            .arg("-f")
+            .arg(format!("body={}", comment))
+            .output()
+            .context("Failed to execute gh api")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh api failed to update the stack comment on {pr_url}: {stderr}");
+        }
+
+        Ok(())
+    }
+
+    fn delete_pr_comment(&self, pr_url: &str, comment_id: &str) -> Result<()> {
+        let api_endpoint = format!(
+            "/repos/{}/issues/comments/{}",
+            self.repository.slug(),
+            comment_id
+        );
+
+        debug!(
+            "Executing command: gh api --hostname {} -X DELETE {}",
+            self.repository.host, api_endpoint
+        );
+
+        let output = Command::new("gh")
+            .arg("api")
+            .arg("--hostname")
+            .arg(&self.repository.host)
+            .arg("-X")
+            .arg("DELETE")
+            .arg(&api_endpoint)
+            .output()
+            .context("Failed to execute gh api")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh api failed to delete comment {comment_id} on {pr_url}: {stderr}");
+        }
+
+        Ok(())
+    }
+
+    fn close_pr(&self, pr_url: &str) -> Result<()> {
+        debug!("Executing command: gh pr close {}", pr_url);
+
+        let output = Command::new("gh")
+            .arg("pr")
+            .arg("close")
+            .arg(pr_url)
+            .output()
+            .context("Failed to execute gh pr close")?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            anyhow::bail!("gh pr close failed: {stderr}");
+        }
+
+        Ok(())
+    }
+
+    fn fetch_pr_statuses(&self, branches: &[&str]) -> Result<HashMap<String, PrStatus>> {
+        if branches.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let data = self.graphql(&status_query(branches)?)?;
+        Ok(pr_statuses(branches, data, self.head_owner()))
     }
-    Ok(())
 }
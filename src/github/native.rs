@@ -0,0 +1,455 @@
+use super::{pr_cache, pr_head, pr_query, pr_statuses, status_query, GraphQlData, GraphQlResponse};
+use crate::forge::{pr_number, Forge, PrCache, PrStatus};
+use crate::http::{self, ApiError, Client};
+use crate::remote::Repository;
+use anyhow::{Context, Result};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Talks to the GitHub REST and GraphQL APIs directly, without the GitHub CLI
+pub struct NativeClient {
+    client: Client,
+    graphql_url: String,
+    owner: String,
+    repo: String,
+    /// Owner of the fork the branches are pushed to, if not the repository itself
+    head_owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RepositoryInfo {
+    default_branch: String,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    html_url: String,
+    node_id: String,
+}
+
+#[derive(Deserialize)]
+struct NewComment {
+    id: u64,
+}
+
+#[derive(Serialize)]
+struct NewPullRequest<'a> {
+    title: &'a str,
+    head: &'a str,
+    base: &'a str,
+    body: &'a str,
+    draft: bool,
+}
+
+impl NativeClient {
+    /// Set up a client for `repository` at `api_url`, authenticated with the token from
+    /// the environment or gh's config file
+    pub fn new(repository: &Repository, head_owner: Option<String>, api_url: &str) -> Result<Self> {
+        // The variables gh reads, depending on whether the host is github.com
+        let host = &repository.host;
+        let variables = if host == "github.com" {
+            ["GH_TOKEN", "GITHUB_TOKEN"]
+        } else {
+            ["GH_ENTERPRISE_TOKEN", "GITHUB_ENTERPRISE_TOKEN"]
+        };
+        let token = http::token(
+            host,
+            &variables,
+            gh_hosts_file(),
+            hosts_file_token,
+            &format!("gh auth login --hostname {host} --insecure-storage"),
+        )?;
+        debug!("Using GitHub API at {api_url} for {}", repository.slug());
+
+        let client = Client::new(
+            api_url,
+            vec![
+                ("Authorization".to_string(), format!("Bearer {token}")),
+                (
+                    "Accept".to_string(),
+                    "application/vnd.github+json".to_string(),
+                ),
+                ("X-GitHub-Api-Version".to_string(), "2022-11-28".to_string()),
+                ("User-Agent".to_string(), "stack-prs".to_string()),
+            ],
+        );
+
+        Ok(NativeClient {
+            graphql_url: graphql_url(client.base_url()),
+            client,
+            owner: repository.owner.clone(),
+            repo: repository.name.clone(),
+            head_owner,
+        })
+    }
+
+    fn repo_path(&self) -> String {
+        format!("/repos/{}/{}", self.owner, self.repo)
+    }
+
+    fn graphql<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> Result<T, ApiError> {
+        let envelope: GraphQlResponse<T> = self.client.send(
+            "POST",
+            &self.graphql_url,
+            &json!({ "query": query, "variables": variables }),
+        )?;
+
+        if !envelope.errors.is_empty() {
+            return Err(ApiError::GraphQl(
+                envelope.errors.into_iter().map(|e| e.message).collect(),
+            ));
+        }
+        envelope
+            .data
+            .ok_or_else(|| ApiError::Decode("GraphQL response without data".to_string()))
+    }
+
+    fn edit_pr(&self, pr_url: &str, fields: serde_json::Value) -> Result<()> {
+        let path = format!("{}/pulls/{}", self.repo_path(), pr_number(pr_url)?);
+        let _: serde_json::Value = self
+            .client
+            .send("PATCH", &path, &fields)
+            .with_context(|| format!("Failed to edit PR {pr_url}"))?;
+        Ok(())
+    }
+}
+
+impl Forge for NativeClient {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn default_branch(&self) -> Result<String> {
+        let repository: RepositoryInfo = self
+            .client
+            .get(&self.repo_path())
+            .context("Failed to look up the repository")?;
+        Ok(repository.default_branch)
+    }
+
+    fn fetch_prs(&self, branches: &[&str]) -> Result<PrCache> {
+        if branches.is_empty() {
+            return Ok(PrCache::default());
+        }
+
+        let data: GraphQlData<_> = self
+            .graphql(
+                &pr_query(branches)?,
+                json!({ "owner": self.owner, "name": self.repo }),
+            )
+            .context("Failed to fetch PRs")?;
+
+        let head_owner = self.head_owner.as_deref().unwrap_or(&self.owner);
+        pr_cache(branches, &data, head_owner, |query| {
+            Ok(self.graphql(query, json!({ "owner": self.owner, "name": self.repo }))?)
+        })
+    }
+
+    fn create_pr(
+        &self,
+        head_branch: &str,
+        base_branch: &str,
+        title: &str,
+        body: &str,
+        draft: bool,
+    ) -> Result<String> {
+        let pr: PullRequest = self
+            .client
+            .send(
+                "POST",
+                &format!("{}/pulls", self.repo_path()),
+                &NewPullRequest {
+                    title,
+                    head: &pr_head(self.head_owner.as_deref(), head_branch),
+                    base: base_branch,
+                    body,
+                    draft,
+                },
+            )
+            .with_context(|| format!("Failed to create PR for {head_branch}"))?;
+
+        println!("PR created: {}", pr.html_url);
+        Ok(pr.html_url)
+    }
+
+    fn add_pr_comment(&self, pr_url: &str, comment: &str) -> Result<String> {
+        let path = format!(
+            "{}/issues/{}/comments",
+            self.repo_path(),
+            pr_number(pr_url)?
+        );
+        let comment: NewComment = self
+            .client
+            .send("POST", &path, &json!({ "body": comment }))
+            .with_context(|| format!("Failed to comment on PR {pr_url}"))?;
+        Ok(comment.id.to_string())
+    }
+
+    fn update_pr_comment(&self, pr_url: &str, comment_id: &str, comment: &str) -> Result<()> {
+        let path = format!("{}/issues/comments/{comment_id}", self.repo_path());
+        let _: serde_json::Value = self
+            .client
+            .send("PATCH", &path, &json!({ "body": comment }))
+            .with_context(|| format!("Failed to update comment {comment_id} on PR {pr_url}"))?;
+        Ok(())
+    }
+
+    fn delete_pr_comment(&self, pr_url: &str, comment_id: &str) -> Result<()> {
+        let path = format!("{}/issues/comments/{comment_id}", self.repo_path());
+        self.client
+            .delete(&path)
+            .with_context(|| format!("Failed to delete comment {comment_id} on PR {pr_url}"))
+    }
+
+    fn edit_pr_base(&self, pr_url: &str, base_branch: &str) -> Result<()> {
+        self.edit_pr(pr_url, json!({ "base": base_branch }))
+    }
+
+    fn set_pr_draft(&self, pr_url: &str, draft: bool) -> Result<()> {
+        // The REST API cannot change the draft state, GraphQL needs the PR's node id
+        let path = format!("{}/pulls/{}", self.repo_path(), pr_number(pr_url)?);
+        let pr: PullRequest = self
+            .client
+            .get(&path)
+            .with_context(|| format!("Failed to look up PR {pr_url}"))?;
+
+        let mutation = if draft {
+            "mutation($id: ID!) { convertPullRequestToDraft(input: {pullRequestId: $id}) { clientMutationId } }"
+        } else {
+            "mutation($id: ID!) { markPullRequestReadyForReview(input: {pullRequestId: $id}) { clientMutationId } }"
+        };
+        let _: serde_json::Value = self
+            .graphql(mutation, json!({ "id": pr.node_id }))
+            .with_context(|| format!("Failed to change the draft state of PR {pr_url}"))?;
+        Ok(())
+    }
+
+    fn edit_pr_title(&self, pr_url: &str, title: &str) -> Result<()> {
+        self.edit_pr(pr_url, json!({ "title": title }))
+    }
+
+    fn edit_pr_body(&self, pr_url: &str, body: &str) -> Result<()> {
+        self.edit_pr(pr_url, json!({ "body": body }))
+    }
+
+    fn close_pr(&self, pr_url: &str) -> Result<()> {
+        self.edit_pr(pr_url, json!({ "state": "closed" }))
+    }
+
+    fn fetch_pr_statuses(&self, branches: &[&str]) -> Result<HashMap<String, PrStatus>> {
+        if branches.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let data = self
+            .graphql(
+                &status_query(branches)?,
+                json!({ "owner": self.owner, "name": self.repo }),
+            )
+            .context("Failed to fetch the status of PRs")?;
+
+        let head_owner = self.head_owner.as_deref().unwrap_or(&self.owner);
+        Ok(pr_statuses(branches, data, head_owner))
+    }
+}
+
+/// The GraphQL endpoint next to the REST API at `api_url`.
+/// GitHub Enterprise serves REST under /api/v3 and GraphQL under /api/graphql.
+fn graphql_url(api_url: &str) -> String {
+    match api_url.strip_suffix("/api/v3") {
+        Some(root) => format!("{root}/api/graphql"),
+        None => format!("{api_url}/graphql"),
+    }
+}
+
+fn gh_hosts_file() -> Option<PathBuf> {
+    let config_dir = if let Some(dir) = std::env::var_os("GH_CONFIG_DIR") {
+        PathBuf::from(dir)
+    } else if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        PathBuf::from(dir).join("gh")
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?)
+            .join(".config")
+            .join("gh")
+    };
+    Some(config_dir.join("hosts.yml"))
+}
+
+/// Read `oauth_token` of a host from gh's `hosts.yml`, which looks like
+///
+/// ```yaml
+/// github.com:
+///     users:
+///         octocat:
+///             oauth_token: gho_...
+///         hubot:
+///             oauth_token: gho_...
+///     oauth_token: gho_...
+///     user: octocat
+/// ```
+///
+/// The host's own `oauth_token` is the active `user`'s. Without it, the token comes from
+/// the active user's entry under `users`, which gh writes since it supports multiple accounts.
+/// Tokens that gh keeps in the system keyring are not in this file.
+fn hosts_file_token(contents: &str, host: &str) -> Option<String> {
+    let mut in_host = false;
+    let mut key_indent = None;
+    let mut section = String::new();
+    let mut account = None;
+    let mut token = None;
+    let mut active_user = None;
+    let mut user_tokens = Vec::new();
+
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        if indent == 0 {
+            in_host = trimmed.trim_end_matches(':') == host;
+            key_indent = None;
+            continue;
+        }
+        if !in_host {
+            continue;
+        }
+
+        let Some((key, value)) = trimmed.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().trim_matches(['"', '\'']);
+        if indent == *key_indent.get_or_insert(indent) {
+            section = key.to_string();
+            match key {
+                "oauth_token" if !value.is_empty() => token = Some(value.to_string()),
+                "user" => active_user = Some(value.to_string()),
+                _ => {}
+            }
+        } else if section == "users" {
+            if value.is_empty() {
+                account = Some(key.to_string());
+            } else if let (Some(account), "oauth_token") = (&account, key) {
+                user_tokens.push((account.clone(), value.to_string()));
+            }
+        }
+    }
+
+    token.or_else(|| {
+        user_tokens
+            .iter()
+            .find(|(user, _)| active_user.as_ref() == Some(user))
+            .or(user_tokens.first())
+            .map(|(_, token)| token.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::test_server;
+
+    #[test]
+    fn hosts_file_token_reads_the_host_entry() {
+        let contents = "github.com:\n    oauth_token: gho_public\n    user: octocat\nghe.example.com:\n    git_protocol: ssh\n    oauth_token: \"gho_enterprise\"\n";
+        assert_eq!(
+            hosts_file_token(contents, "github.com").as_deref(),
+            Some("gho_public")
+        );
+        assert_eq!(
+            hosts_file_token(contents, "ghe.example.com").as_deref(),
+            Some("gho_enterprise")
+        );
+        assert_eq!(hosts_file_token(contents, "example.com"), None);
+    }
+
+    #[test]
+    fn hosts_file_token_picks_the_active_account() {
+        let contents = "github.com:\n    users:\n        hubot:\n            oauth_token: gho_hubot\n        octocat:\n            oauth_token: gho_octocat\n    git_protocol: https\n    user: octocat\n";
+        assert_eq!(
+            hosts_file_token(contents, "github.com").as_deref(),
+            Some("gho_octocat")
+        );
+
+        let with_host_token = format!("{contents}    oauth_token: gho_active\n");
+        assert_eq!(
+            hosts_file_token(&with_host_token, "github.com").as_deref(),
+            Some("gho_active")
+        );
+
+        // Accounts whose token lives in the keyring have no oauth_token
+        let keyring = "github.com:\n    users:\n        octocat:\n    user: octocat\n";
+        assert_eq!(hosts_file_token(keyring, "github.com"), None);
+    }
+
+    #[test]
+    fn graphql_url_sits_next_to_the_rest_api() {
+        assert_eq!(
+            graphql_url("https://api.github.com"),
+            "https://api.github.com/graphql"
+        );
+        assert_eq!(
+            graphql_url("https://ghe.example.com/api/v3"),
+            "https://ghe.example.com/api/graphql"
+        );
+    }
+
+    fn client(url: &str) -> NativeClient {
+        NativeClient {
+            client: Client::new(url, vec![]),
+            graphql_url: graphql_url(url),
+            owner: "up".to_string(),
+            repo: "demo".to_string(),
+            head_owner: Some("octo".to_string()),
+        }
+    }
+
+    #[test]
+    fn fetch_prs_keeps_the_prs_from_the_fork() {
+        let (url, server) = test_server::serve(vec![(
+            200,
+            r###"{"data":{"repository":{"pr0":{"nodes":[
+                {"number":3,"url":"https://github.com/up/demo/pull/3","title":"Same branch","body":"","baseRefName":"main","isDraft":false,"headRepositoryOwner":{"login":"up"},"comments":{"nodes":[],"pageInfo":{"hasNextPage":false,"endCursor":null}}},
+                {"number":4,"url":"https://github.com/up/demo/pull/4","title":"First","body":"Body","baseRefName":"main","isDraft":true,"headRepositoryOwner":{"login":"Octo"},"comments":{"nodes":[{"databaseId":41,"body":"## Stack Information"}],"pageInfo":{"hasNextPage":false,"endCursor":null}}}
+            ]},"pr1":{"nodes":[]}}}}"###,
+        )]);
+
+        let prs = client(&url).fetch_prs(&["feat-a", "feat-b"]).unwrap();
+        let pr = prs.get("feat-a").unwrap();
+        assert_eq!(pr.number, 4);
+        assert!(pr.is_draft);
+        assert_eq!(pr.stack_comment.as_ref().unwrap().id, "41");
+        assert!(prs.get("feat-b").is_none());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].path, "/graphql");
+        let request: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(
+            request["variables"],
+            json!({ "owner": "up", "name": "demo" })
+        );
+    }
+
+    #[test]
+    fn graphql_errors_are_reported() {
+        let (url, server) = test_server::serve(vec![(
+            200,
+            r#"{"data":null,"errors":[{"message":"Could not resolve to a Repository with the name 'up/demo'."}]}"#,
+        )]);
+
+        let error = client(&url).fetch_prs(&["feat-a"]).unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "Failed to fetch PRs: GraphQL errors: Could not resolve to a Repository with the name 'up/demo'."
+        );
+        server.join().unwrap();
+    }
+}
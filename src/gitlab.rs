@@ -0,0 +1,569 @@
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::forge::{
+    pr_number, Checks, Comment, Forge, PrCache, PrInfo, PrState, PrStatus, Review,
+    STACK_COMMENT_HEADING,
+};
+use crate::http::{self, ApiError, Client};
+use crate::remote::Repository;
+
+/// Title prefix that marks a merge request as draft
+const DRAFT_PREFIX: &str = "Draft: ";
+
+/// Talks to the GitLab REST API v4. Merge requests play the role of PRs.
+pub struct GitLab {
+    client: Client,
+    /// URL-encoded `group/project`, the API accepts it in place of the numeric project id
+    project: String,
+    /// URL-encoded path of the fork the branches are pushed to, if not `project`
+    source_project: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Project {
+    id: u64,
+    default_branch: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MergeRequest {
+    iid: u64,
+    web_url: String,
+    title: String,
+    description: Option<String>,
+    target_branch: String,
+    project_id: u64,
+    source_project_id: u64,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    state: String,
+    /// Only included when fetching a single merge request
+    #[serde(default)]
+    head_pipeline: Option<Pipeline>,
+}
+
+#[derive(Deserialize)]
+struct Pipeline {
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct Approvals {
+    #[serde(default)]
+    approved: bool,
+    #[serde(default)]
+    approvals_left: u64,
+}
+
+#[derive(Deserialize)]
+struct Note {
+    id: u64,
+    body: String,
+    #[serde(default)]
+    system: bool,
+}
+
+#[derive(Serialize)]
+struct NewMergeRequest<'a> {
+    source_branch: &'a str,
+    target_branch: &'a str,
+    title: &'a str,
+    description: &'a str,
+    /// Set when opening a merge request from a fork
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_project_id: Option<u64>,
+}
+
+/// The API URL of a GitLab host
+pub fn api_url(host: &str) -> String {
+    format!("https://{host}/api/v4")
+}
+
+impl GitLab {
+    /// Set up a client for `repository` at `api_url`, authenticated with the token from
+    /// the environment or glab's config file
+    pub fn new(
+        repository: &Repository,
+        source_repository: Option<&Repository>,
+        api_url: &str,
+    ) -> Result<Self> {
+        let host = &repository.host;
+        let token = http::token(
+            host,
+            &["GITLAB_TOKEN", "GITLAB_ACCESS_TOKEN"],
+            glab_config_file(),
+            config_file_token,
+            &format!("glab auth login --hostname {host}"),
+        )?;
+        debug!("Using GitLab API at {api_url} for {}", repository.slug());
+
+        let client = Client::new(
+            api_url,
+            vec![
+                ("PRIVATE-TOKEN".to_string(), token),
+                ("User-Agent".to_string(), "stack-prs".to_string()),
+            ],
+        );
+
+        Ok(GitLab {
+            client,
+            project: encode(&repository.slug()),
+            source_project: source_repository.map(|r| encode(&r.slug())),
+        })
+    }
+
+    fn project(&self, project: &str) -> Result<Project> {
+        self.client
+            .get(&format!("/projects/{project}"))
+            .with_context(|| format!("Failed to look up the project {project}"))
+    }
+
+    fn merge_request_path(&self, pr_url: &str) -> Result<String> {
+        Ok(format!(
+            "/projects/{}/merge_requests/{}",
+            self.project,
+            pr_number(pr_url)?
+        ))
+    }
+
+    fn merge_request(&self, pr_url: &str) -> Result<MergeRequest> {
+        self.client
+            .get(&self.merge_request_path(pr_url)?)
+            .with_context(|| format!("Failed to look up merge request {pr_url}"))
+    }
+
+    fn edit_merge_request(&self, pr_url: &str, fields: serde_json::Value) -> Result<()> {
+        let _: serde_json::Value = self
+            .client
+            .send("PUT", &self.merge_request_path(pr_url)?, &fields)
+            .with_context(|| format!("Failed to edit merge request {pr_url}"))?;
+        Ok(())
+    }
+
+    /// The id of the project branches are pushed to, if it is a fork
+    fn source_project_id(&self) -> Result<Option<u64>> {
+        match &self.source_project {
+            Some(source_project) => Ok(Some(self.project(source_project)?.id)),
+            None => Ok(None),
+        }
+    }
+
+    fn stack_comment(&self, iid: u64) -> Result<Option<Comment>, ApiError> {
+        let notes: Vec<Note> = self.client.get(&format!(
+            "/projects/{}/merge_requests/{iid}/notes?sort=asc&per_page=100",
+            self.project
+        ))?;
+
+        // If multiple stack comments exist, take the first one
+        Ok(notes
+            .into_iter()
+            .find(|note| !note.system && note.body.contains(STACK_COMMENT_HEADING))
+            .map(|note| Comment {
+                id: note.id.to_string(),
+                body: note.body,
+            }))
+    }
+}
+
+impl Forge for GitLab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn default_branch(&self) -> Result<String> {
+        self.project(&self.project)?
+            .default_branch
+            .context("The project has no default branch")
+    }
+
+    fn fetch_prs(&self, branches: &[&str]) -> Result<PrCache> {
+        // The REST API has no batch lookup, so this takes two requests per branch
+        let mut cache = PrCache::default();
+        if branches.is_empty() {
+            return Ok(cache);
+        }
+
+        // Forks can have branches of the same name, so the source project has to match too
+        let source_project_id = self.source_project_id()?;
+
+        for branch in branches {
+            let merge_requests: Vec<MergeRequest> = self
+                .client
+                .get(&format!(
+                    "/projects/{}/merge_requests?state=opened&source_branch={}",
+                    self.project,
+                    encode(branch)
+                ))
+                .with_context(|| format!("Failed to look up merge requests for {branch}"))?;
+
+            let Some(mr) = merge_requests
+                .into_iter()
+                .find(|mr| mr.source_project_id == source_project_id.unwrap_or(mr.project_id))
+            else {
+                continue;
+            };
+
+            let stack_comment = self
+                .stack_comment(mr.iid)
+                .with_context(|| format!("Failed to read the comments of {}", mr.web_url))?;
+
+            cache.insert(
+                branch,
+                PrInfo {
+                    number: mr.iid,
+                    title: strip_draft(&mr.title).to_string(),
+                    url: mr.web_url,
+                    body: mr.description.unwrap_or_default(),
+                    base: mr.target_branch,
+                    is_draft: mr.draft,
+                    stack_comment,
+                },
+            );
+        }
+        Ok(cache)
+    }
+
+    fn create_pr(
+        &self,
+        head_branch: &str,
+        base_branch: &str,
+        title: &str,
+        body: &str,
+        draft: bool,
+    ) -> Result<String> {
+        let title = if draft {
+            format!("{DRAFT_PREFIX}{title}")
+        } else {
+            title.to_string()
+        };
+
+        // Merge requests from a fork are created in the fork and point at the target project
+        let (project, target_project_id) = match &self.source_project {
+            Some(source_project) => (source_project, Some(self.project(&self.project)?.id)),
+            None => (&self.project, None),
+        };
+
+        let mr: MergeRequest = self
+            .client
+            .send(
+                "POST",
+                &format!("/projects/{project}/merge_requests"),
+                &NewMergeRequest {
+                    source_branch: head_branch,
+                    target_branch: base_branch,
+                    title: &title,
+                    description: body,
+                    target_project_id,
+                },
+            )
+            .with_context(|| format!("Failed to create merge request for {head_branch}"))?;
+
+        println!("MR created: {}", mr.web_url);
+        Ok(mr.web_url)
+    }
+
+    fn add_pr_comment(&self, pr_url: &str, comment: &str) -> Result<String> {
+        let path = format!("{}/notes", self.merge_request_path(pr_url)?);
+        let note: Note = self
+            .client
+            .send("POST", &path, &json!({ "body": comment }))
+            .with_context(|| format!("Failed to comment on merge request {pr_url}"))?;
+        Ok(note.id.to_string())
+    }
+
+    fn update_pr_comment(&self, pr_url: &str, comment_id: &str, comment: &str) -> Result<()> {
+        let path = format!("{}/notes/{comment_id}", self.merge_request_path(pr_url)?);
+        let _: serde_json::Value = self
+            .client
+            .send("PUT", &path, &json!({ "body": comment }))
+            .with_context(|| {
+                format!("Failed to update comment {comment_id} on merge request {pr_url}")
+            })?;
+        Ok(())
+    }
+
+    fn delete_pr_comment(&self, pr_url: &str, comment_id: &str) -> Result<()> {
+        let path = format!("{}/notes/{comment_id}", self.merge_request_path(pr_url)?);
+        self.client.delete(&path).with_context(|| {
+            format!("Failed to delete comment {comment_id} on merge request {pr_url}")
+        })
+    }
+
+    fn edit_pr_base(&self, pr_url: &str, base_branch: &str) -> Result<()> {
+        self.edit_merge_request(pr_url, json!({ "target_branch": base_branch }))
+    }
+
+    fn set_pr_draft(&self, pr_url: &str, draft: bool) -> Result<()> {
+        // GitLab tracks the draft state through a title prefix
+        let mr = self.merge_request(pr_url)?;
+        let title = strip_draft(&mr.title);
+        let title = if draft {
+            format!("{DRAFT_PREFIX}{title}")
+        } else {
+            title.to_string()
+        };
+        self.edit_merge_request(pr_url, json!({ "title": title }))
+    }
+
+    fn edit_pr_title(&self, pr_url: &str, title: &str) -> Result<()> {
+        // Keep the draft prefix, replacing it would mark the merge request as ready
+        let mr = self.merge_request(pr_url)?;
+        let title = if mr.draft {
+            format!("{DRAFT_PREFIX}{title}")
+        } else {
+            title.to_string()
+        };
+        self.edit_merge_request(pr_url, json!({ "title": title }))
+    }
+
+    fn edit_pr_body(&self, pr_url: &str, body: &str) -> Result<()> {
+        self.edit_merge_request(pr_url, json!({ "description": body }))
+    }
+
+    fn close_pr(&self, pr_url: &str) -> Result<()> {
+        self.edit_merge_request(pr_url, json!({ "state_event": "close" }))
+    }
+
+    fn fetch_pr_statuses(&self, branches: &[&str]) -> Result<HashMap<String, PrStatus>> {
+        // The pipeline is only part of a single merge request, and approvals have their own
+        // endpoint, so this takes three requests per branch
+        let mut statuses = HashMap::new();
+        if branches.is_empty() {
+            return Ok(statuses);
+        }
+
+        let source_project_id = self.source_project_id()?;
+
+        for branch in branches {
+            // Newest first, so a branch that was reused after a merge shows its latest MR
+            let merge_requests: Vec<MergeRequest> = self
+                .client
+                .get(&format!(
+                    "/projects/{}/merge_requests?state=all&order_by=created_at&sort=desc&source_branch={}",
+                    self.project,
+                    encode(branch)
+                ))
+                .with_context(|| format!("Failed to look up merge requests for {branch}"))?;
+
+            let Some(mr) = merge_requests
+                .into_iter()
+                .find(|mr| mr.source_project_id == source_project_id.unwrap_or(mr.project_id))
+            else {
+                continue;
+            };
+
+            let path = format!("/projects/{}/merge_requests/{}", self.project, mr.iid);
+            let mr: MergeRequest = self
+                .client
+                .get(&path)
+                .with_context(|| format!("Failed to look up merge request {}", mr.web_url))?;
+            let approvals: Approvals = self
+                .client
+                .get(&format!("{path}/approvals"))
+                .with_context(|| format!("Failed to read the approvals of {}", mr.web_url))?;
+
+            let state = match mr.state.as_str() {
+                "merged" => PrState::Merged,
+                "closed" | "locked" => PrState::Closed,
+                _ if mr.draft => PrState::Draft,
+                _ => PrState::Open,
+            };
+            let review = if approvals.approved {
+                Some(Review::Approved)
+            } else if approvals.approvals_left > 0 {
+                Some(Review::Required)
+            } else {
+                None
+            };
+            let checks = mr
+                .head_pipeline
+                .map(|pipeline| match pipeline.status.as_str() {
+                    "success" => Checks::Passing,
+                    "failed" | "canceled" => Checks::Failing,
+                    _ => Checks::Pending,
+                });
+
+            statuses.insert(
+                branch.to_string(),
+                PrStatus {
+                    number: mr.iid,
+                    url: mr.web_url,
+                    state,
+                    review,
+                    checks,
+                },
+            );
+        }
+        Ok(statuses)
+    }
+}
+
+/// Remove the prefixes GitLab recognizes as marking a draft
+fn strip_draft(title: &str) -> &str {
+    ["Draft:", "[Draft]", "(Draft)"]
+        .iter()
+        .find_map(|prefix| title.strip_prefix(prefix))
+        .map_or(title, str::trim_start)
+}
+
+/// Percent-encode a path segment or query value
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn glab_config_file() -> Option<PathBuf> {
+    let config_dir = if let Some(dir) = std::env::var_os("GLAB_CONFIG_DIR") {
+        PathBuf::from(dir)
+    } else if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        PathBuf::from(dir).join("glab-cli")
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?)
+            .join(".config")
+            .join("glab-cli")
+    };
+    Some(config_dir.join("config.yml"))
+}
+
+/// Read the `token` of a host from glab's `config.yml`, which looks like
+///
+/// ```yaml
+/// hosts:
+///     gitlab.com:
+///         token: glpat-...
+///         api_protocol: https
+/// ```
+///
+/// Tokens that glab keeps in the system keyring are not in this file.
+fn config_file_token(contents: &str, host: &str) -> Option<String> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+
+    let mut host_indent = None;
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match host_indent {
+            Some(level) if indent(line) > level => {
+                if let Some(token) = line.trim().strip_prefix("token:") {
+                    let token = token.trim().trim_matches(['"', '\'']);
+                    if !token.is_empty() {
+                        return Some(token.to_string());
+                    }
+                }
+            }
+            _ => {
+                host_indent = (line.trim().trim_end_matches(':') == host).then(|| indent(line));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::test_server;
+
+    #[test]
+    fn config_file_token_reads_the_host_entry() {
+        let contents = "git_protocol: ssh\nhosts:\n    gitlab.com:\n        api_protocol: https\n        token: glpat-public\n        user: octo\n    gitlab.example.com:\n        api_host: gitlab.example.com\n        token: \"glpat-work\"\n    empty.example.com:\n        token:\n    other.example.com:\n        token: glpat-other\n";
+        assert_eq!(
+            config_file_token(contents, "gitlab.com").as_deref(),
+            Some("glpat-public")
+        );
+        assert_eq!(
+            config_file_token(contents, "gitlab.example.com").as_deref(),
+            Some("glpat-work")
+        );
+        assert_eq!(config_file_token(contents, "empty.example.com"), None);
+        assert_eq!(config_file_token(contents, "example.com"), None);
+    }
+
+    #[test]
+    fn strip_draft_removes_draft_prefixes() {
+        assert_eq!(strip_draft("Draft: Add login"), "Add login");
+        assert_eq!(strip_draft("[Draft] Add login"), "Add login");
+        assert_eq!(strip_draft("(Draft)Add login"), "Add login");
+        assert_eq!(strip_draft("Add Draft: login"), "Add Draft: login");
+        assert_eq!(strip_draft("Add login"), "Add login");
+    }
+
+    #[test]
+    fn encode_escapes_everything_but_unreserved_characters() {
+        assert_eq!(encode("group/sub/project"), "group%2Fsub%2Fproject");
+        assert_eq!(encode("feat/a b+c"), "feat%2Fa%20b%2Bc");
+        assert_eq!(encode("push-abc_1.2~"), "push-abc_1.2~");
+        assert_eq!(encode("ü"), "%C3%BC");
+    }
+
+    #[test]
+    fn fetch_prs_keeps_the_merge_requests_from_the_fork() {
+        // Responses of the GitLab API, trimmed to the fields stack-prs reads
+        let (url, server) = test_server::serve(vec![
+            (200, r#"{"id":20,"default_branch":"main"}"#),
+            (
+                200,
+                r#"[
+                    {"iid":7,"web_url":"https://gitlab.com/up/demo/-/merge_requests/7","title":"Same branch upstream","description":null,"target_branch":"main","project_id":10,"source_project_id":10,"draft":false},
+                    {"iid":6,"web_url":"https://gitlab.com/up/demo/-/merge_requests/6","title":"Draft: First","description":"Body","target_branch":"main","project_id":10,"source_project_id":20,"draft":true}
+                ]"#,
+            ),
+            (
+                200,
+                r###"[{"id":30,"body":"## Stack Information","system":true},{"id":31,"body":"## Stack Information\n\n* !6","system":false}]"###,
+            ),
+            (
+                200,
+                r#"[{"iid":5,"web_url":"https://gitlab.com/up/demo/-/merge_requests/5","title":"Second","description":"","target_branch":"feat/a","project_id":10,"source_project_id":10}]"#,
+            ),
+        ]);
+        let repository = |owner: &str| Repository {
+            host: "gitlab.com".to_string(),
+            owner: owner.to_string(),
+            name: "demo".to_string(),
+        };
+        let gitlab = GitLab {
+            client: Client::new(&url, vec![]),
+            project: encode(&repository("up").slug()),
+            source_project: Some(encode(&repository("octo").slug())),
+        };
+
+        let prs = gitlab.fetch_prs(&["feat/a", "feat/b"]).unwrap();
+        let pr = prs.get("feat/a").unwrap();
+        assert_eq!(pr.number, 6);
+        assert_eq!(pr.title, "First");
+        assert!(pr.is_draft);
+        assert_eq!(pr.stack_comment.as_ref().unwrap().id, "31");
+        assert!(prs.get("feat/b").is_none());
+
+        let paths: Vec<_> = server
+            .join()
+            .unwrap()
+            .into_iter()
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/projects/octo%2Fdemo",
+                "/projects/up%2Fdemo/merge_requests?state=opened&source_branch=feat%2Fa",
+                "/projects/up%2Fdemo/merge_requests/6/notes?sort=asc&per_page=100",
+                "/projects/up%2Fdemo/merge_requests?state=opened&source_branch=feat%2Fb",
+            ]
+        );
+    }
+}
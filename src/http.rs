@@ -0,0 +1,345 @@
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Errors returned by forge HTTP APIs
+#[derive(Debug)]
+pub enum ApiError {
+    /// No token could be found in the environment or the CLI's config file
+    MissingToken(String),
+    /// 401, the token is missing, invalid or expired
+    Unauthorized(String),
+    /// 403, the token lacks a scope or we got rate limited
+    Forbidden(String),
+    /// 404, also what GitHub answers for private repositories the token cannot see
+    NotFound(String),
+    /// 422, the request was understood but rejected, e.g. a PR already exists
+    Unprocessable(String),
+    /// Any other non-success status
+    Status { status: u16, message: String },
+    /// The server could not be reached
+    Transport(String),
+    /// The response did not have the expected shape
+    Decode(String),
+    /// A GraphQL request succeeded but reported errors
+    GraphQl(Vec<String>),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::MissingToken(hint) => write!(f, "no API token found, {hint}"),
+            ApiError::Unauthorized(message) => write!(f, "authentication failed: {message}"),
+            ApiError::Forbidden(message) => write!(f, "permission denied: {message}"),
+            ApiError::NotFound(message) => write!(f, "not found: {message}"),
+            ApiError::Unprocessable(message) => write!(f, "request rejected: {message}"),
+            ApiError::Status { status, message } => write!(f, "HTTP {status}: {message}"),
+            ApiError::Transport(message) => write!(f, "request failed: {message}"),
+            ApiError::Decode(message) => write!(f, "unexpected response: {message}"),
+            ApiError::GraphQl(messages) => write!(f, "GraphQL errors: {}", messages.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// A small JSON-over-HTTP client for one API base URL
+pub struct Client {
+    agent: ureq::Agent,
+    base_url: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Client {
+    /// Create a client that sends `headers` with every request. `base_url` has no trailing slash.
+    pub fn new(base_url: &str, headers: Vec<(String, String)>) -> Self {
+        Client {
+            agent: ureq::AgentBuilder::new().build(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers,
+        }
+    }
+
+    /// The base URL requests are made against
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// GET a path below the base URL and decode the JSON response
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ApiError> {
+        self.call("GET", path, None::<&()>)
+    }
+
+    /// Send a JSON body to a path below the base URL and decode the JSON response
+    pub fn send<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        body: &B,
+    ) -> Result<T, ApiError> {
+        self.call(method, path, Some(body))
+    }
+
+    /// DELETE a path below the base URL, ignoring the (usually empty) response
+    pub fn delete(&self, path: &str) -> Result<(), ApiError> {
+        self.request("DELETE", path, None::<&()>).map(|_| ())
+    }
+
+    fn call<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<T, ApiError> {
+        let response = self.request(method, path, body)?;
+        let url = response.get_url().to_string();
+        response
+            .into_json()
+            .map_err(|e| ApiError::Decode(format!("{method} {url}: {e}")))
+    }
+
+    fn request<B: Serialize>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<ureq::Response, ApiError> {
+        let url = if path.starts_with("http://") || path.starts_with("https://") {
+            path.to_string()
+        } else {
+            format!("{}{path}", self.base_url)
+        };
+        debug!("HTTP request: {method} {url}");
+
+        let mut request = self.agent.request(method, &url);
+        for (name, value) in &self.headers {
+            request = request.set(name, value);
+        }
+
+        let result = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+
+        match result {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => {
+                let message = error_message(response.into_string().unwrap_or_default());
+                Err(match status {
+                    401 => ApiError::Unauthorized(message),
+                    403 => ApiError::Forbidden(message),
+                    404 => ApiError::NotFound(format!("{method} {url}: {message}")),
+                    422 => ApiError::Unprocessable(message),
+                    _ => ApiError::Status { status, message },
+                })
+            }
+            Err(ureq::Error::Transport(transport)) => {
+                Err(ApiError::Transport(format!("{method} {url}: {transport}")))
+            }
+        }
+    }
+}
+
+/// Find a token for `host`: the first of the environment `variables` that is set, then the
+/// token `parse` finds for `host` in the CLI's `config_file`. `login` is the command that
+/// stores a token there, suggested when there is none.
+pub fn token(
+    host: &str,
+    variables: &[&str],
+    config_file: Option<PathBuf>,
+    parse: fn(&str, &str) -> Option<String>,
+    login: &str,
+) -> Result<String, ApiError> {
+    for variable in variables {
+        if let Ok(token) = std::env::var(variable) {
+            if !token.trim().is_empty() {
+                debug!("Using the token from {variable}");
+                return Ok(token.trim().to_string());
+            }
+        }
+    }
+
+    if let Some(path) = config_file {
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            if let Some(token) = parse(&contents, host) {
+                debug!("Using the token for {host} from {}", path.display());
+                return Ok(token);
+            }
+        }
+    }
+
+    Err(ApiError::MissingToken(format!(
+        "set {}, or log in with `{login}`",
+        variables.join(" or ")
+    )))
+}
+
+/// Pull a human readable message out of an error response body
+fn error_message(body: String) -> String {
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(&body) else {
+        return body.trim().to_string();
+    };
+
+    // GitLab also sends lists of messages, or objects keyed by field
+    let mut message = match ["message", "error"].iter().find_map(|key| json.get(key)) {
+        Some(serde_json::Value::String(message)) => message.clone(),
+        Some(serde_json::Value::Array(messages)) => messages
+            .iter()
+            .map(|m| m.as_str().map_or_else(|| m.to_string(), str::to_string))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Some(other) => other.to_string(),
+        None => body.trim().to_string(),
+    };
+
+    // GitHub lists validation failures separately, e.g. "A pull request already exists"
+    if let Some(errors) = json.get("errors").and_then(|v| v.as_array()) {
+        let details: Vec<&str> = errors
+            .iter()
+            .filter_map(|e| e.get("message").and_then(|m| m.as_str()))
+            .collect();
+        if !details.is_empty() {
+            message = format!("{message} ({})", details.join(", "));
+        }
+    }
+    message
+}
+
+/// A local HTTP server that replays recorded responses, for testing the forge clients
+#[cfg(test)]
+pub mod test_server {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /// A request the server received
+    #[derive(Debug)]
+    pub struct Request {
+        pub method: String,
+        pub path: String,
+        pub body: String,
+    }
+
+    /// Answer one request after another with `responses`, each a status and a JSON body.
+    /// Returns the server's base URL and a handle that yields the requests once all
+    /// responses have been sent.
+    pub fn serve(responses: Vec<(u16, &str)>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let responses: Vec<(u16, String)> = responses
+            .into_iter()
+            .map(|(status, body)| (status, body.to_string()))
+            .collect();
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_string();
+                let path = parts.next().unwrap_or_default().to_string();
+
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut request_body = vec![0; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                requests.push(Request {
+                    method,
+                    path,
+                    body: String::from_utf8(request_body).unwrap(),
+                });
+
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {status} Recorded\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_message_lists_github_validation_errors() {
+        let body = r#"{"message":"Validation Failed","errors":[{"resource":"PullRequest","code":"custom","message":"A pull request already exists for octo:feat-a."}]}"#;
+        assert_eq!(
+            error_message(body.to_string()),
+            "Validation Failed (A pull request already exists for octo:feat-a.)"
+        );
+    }
+
+    #[test]
+    fn error_message_joins_gitlab_message_arrays() {
+        let body = r#"{"message":["Another open merge request already exists for this source branch: !4"]}"#;
+        assert_eq!(
+            error_message(body.to_string()),
+            "Another open merge request already exists for this source branch: !4"
+        );
+        assert_eq!(
+            error_message(r#"{"error":"insufficient_scope"}"#.to_string()),
+            "insufficient_scope"
+        );
+        assert_eq!(error_message("Bad Gateway\n".to_string()), "Bad Gateway");
+    }
+
+    #[test]
+    fn round_trip_against_a_local_server() {
+        let (url, server) = test_server::serve(vec![
+            (201, r#"{"id":7}"#),
+            (
+                422,
+                r#"{"message":"Validation Failed","errors":[{"message":"No commits between main and feat-a"}]}"#,
+            ),
+        ]);
+        let client = Client::new(&format!("{url}/api/v1/"), vec![]);
+
+        let created: serde_json::Value = client
+            .send(
+                "POST",
+                "/repos/o/r/pulls",
+                &serde_json::json!({ "title": "First" }),
+            )
+            .unwrap();
+        assert_eq!(created["id"], 7);
+
+        let rejected = client.get::<serde_json::Value>("/repos/o/r/pulls/8");
+        match rejected {
+            Err(ApiError::Unprocessable(message)) => assert_eq!(
+                message,
+                "Validation Failed (No commits between main and feat-a)"
+            ),
+            other => panic!("expected a rejected request, got {other:?}"),
+        }
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/api/v1/repos/o/r/pulls");
+        assert_eq!(requests[0].body, r#"{"title":"First"}"#);
+        assert_eq!(requests[1].method, "GET");
+        assert_eq!(requests[1].path, "/api/v1/repos/o/r/pulls/8");
+    }
+}
@@ -1,20 +1,84 @@
 use anyhow::{Context, Result};
 use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process::Command;
 
-#[derive(Debug, Clone)]
+/// A change as `jj log` describes it, see [`CHANGE_TEMPLATE`]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Change {
     pub change_id: String,
+    /// The commit the change is at now, shown next to the change ID by `status`
+    pub commit_id: String,
+    /// The full, possibly multi-line, description
     pub description: String,
-    pub bookmark: Option<String>,
+    /// Names of the local bookmarks pointing at the change
+    #[serde(rename = "local_bookmarks")]
+    pub bookmarks: Vec<String>,
+    /// Bookmarks on remotes pointing at the change, including ones without a local bookmark
+    pub remote_bookmarks: Vec<RemoteBookmark>,
+    /// Change IDs of the parents
+    pub parents: Vec<String>,
+    /// Noted in the stack file when it is not the user
+    pub author: Author,
+    /// Whether the change modifies no files
+    pub empty: bool,
+    /// Whether the change has unresolved conflicts
+    pub conflict: bool,
+    /// Whether several visible commits have this change ID
+    pub divergent: bool,
 }
 
+impl Change {
+    /// The first line of the description
+    pub fn title(&self) -> &str {
+        self.description.lines().next().unwrap_or("").trim()
+    }
+
+    /// Everything after the first line of the description
+    pub fn body(&self) -> &str {
+        self.description
+            .split_once('\n')
+            .map(|(_, body)| body.trim())
+            .unwrap_or("")
+    }
+}
+
+/// A bookmark on a remote pointing at a change, e.g. `main@origin`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteBookmark {
+    pub name: String,
+    pub remote: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+/// Prints every change as one line of JSON. Every string is escaped by jj, so descriptions
+/// may contain anything, including lines that look like separators.
+const CHANGE_TEMPLATE: &str = concat!(
+    "'{'",
+    " ++ '\"change_id\":' ++ stringify(change_id).escape_json()",
+    " ++ ',\"commit_id\":' ++ stringify(commit_id).escape_json()",
+    " ++ ',\"description\":' ++ description.escape_json()",
+    " ++ ',\"local_bookmarks\":[' ++ local_bookmarks.map(|b| stringify(b.name()).escape_json()).join(',') ++ ']'",
+    " ++ ',\"remote_bookmarks\":[' ++ remote_bookmarks.map(|b| '{\"name\":' ++ stringify(b.name()).escape_json() ++ ',\"remote\":' ++ stringify(b.remote()).escape_json() ++ '}').join(',') ++ ']'",
+    " ++ ',\"parents\":[' ++ parents.map(|p| stringify(p.change_id()).escape_json()).join(',') ++ ']'",
+    " ++ ',\"author\":{\"name\":' ++ author.name().escape_json() ++ ',\"email\":' ++ stringify(author.email()).escape_json() ++ '}'",
+    " ++ ',\"empty\":' ++ if(empty, 'true', 'false')",
+    " ++ ',\"conflict\":' ++ if(conflict, 'true', 'false')",
+    " ++ ',\"divergent\":' ++ if(divergent, 'true', 'false')",
+    " ++ \"}\\n\"",
+);
+
 /// Get all changes for given revisions
 pub fn get_changes(revisions: &str) -> Result<Vec<Change>> {
-    let template_arg = "change_id ++ \"\\n\" ++ description.trim() ++ \"\\n\" ++ local_bookmarks.join(\",\") ++ \"\\n---\\n\"";
-
     debug!(
-        "Executing command: jj log --no-graph --revisions {revisions} --template {template_arg}",
+        "Executing command: jj log --no-graph --revisions {revisions} --template {CHANGE_TEMPLATE}",
     );
 
     let output = Command::new("jj")
@@ -23,7 +87,7 @@ pub fn get_changes(revisions: &str) -> Result<Vec<Change>> {
         .arg("--revisions")
         .arg(revisions)
         .arg("--template")
-        .arg(template_arg)
+        .arg(CHANGE_TEMPLATE)
         .output()
         .context("Failed to execute jj log command")?;
 
@@ -36,78 +100,109 @@ pub fn get_changes(revisions: &str) -> Result<Vec<Change>> {
     parse_jj_log(&stdout)
 }
 
+/// Parse one change per line. A divergent change is listed once per commit, only the
+/// first of them is kept so that every change ID appears once.
 fn parse_jj_log(output: &str) -> Result<Vec<Change>> {
-    let mut changes = Vec::new();
-    let entries: Vec<&str> = output.split("---\n").collect();
-
-    for entry in entries {
-        let entry = entry.trim();
-        if entry.is_empty() {
-            continue;
+    let mut changes: Vec<Change> = Vec::new();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let change: Change = serde_json::from_str(line)
+            .with_context(|| format!("Failed to parse the jj log output: {line}"))?;
+        if !changes.iter().any(|c| c.change_id == change.change_id) {
+            changes.push(change);
         }
+    }
+    Ok(changes)
+}
 
-        let lines: Vec<&str> = entry.lines().collect();
-        if lines.is_empty() {
-            continue;
-        }
+/// Get the names of the local and remote bookmarks on the change jj's `trunk()` revset
+/// resolves to, sorted and without duplicates
+pub fn trunk_bookmarks() -> Result<Vec<String>> {
+    let template_arg =
+        "remote_bookmarks.map(|b| b.name()).join(\"\\n\") ++ \"\\n\" ++ local_bookmarks.map(|b| b.name()).join(\"\\n\")";
 
-        let change_id = lines[0].trim().to_string();
-        let description = if lines.len() > 1 {
-            lines[1].trim().to_string()
-        } else {
-            String::new()
-        };
+    debug!("Executing command: jj log --no-graph --revisions trunk() --template {template_arg}");
 
-        let bookmark = if lines.len() > 2 && !lines[2].trim().is_empty() {
-            Some(lines[2].trim().to_string())
-        } else {
-            None
-        };
+    let output = Command::new("jj")
+        .arg("log")
+        .arg("--no-graph")
+        .arg("--revisions")
+        .arg("trunk()")
+        .arg("--template")
+        .arg(template_arg)
+        .output()
+        .context("Failed to execute jj log command")?;
 
-        changes.push(Change {
-            change_id,
-            description,
-            bookmark,
-        });
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj log failed: {stderr}");
     }
 
-    Ok(changes)
+    // trunk() falls back to root() when no trunk bookmark exists, which has no bookmarks
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut bookmarks: Vec<String> = stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    bookmarks.sort_unstable();
+    bookmarks.dedup();
+    Ok(bookmarks)
 }
 
-/// Create a bookmark for a specific change
-pub fn create_bookmark(change_id: &str, bookmark_name: &str) -> Result<()> {
+/// Point a bookmark at the current commit of a change, creating it if needed.
+///
+/// Without `allow_backwards`, jj refuses to move an existing bookmark backwards or sideways.
+pub fn set_bookmark(change_id: &str, bookmark_name: &str, allow_backwards: bool) -> Result<()> {
     debug!(
-        "Executing command: jj bookmark create {} --revision {}",
-        bookmark_name, change_id
+        "Executing command: jj bookmark set {} --revision {}{}",
+        bookmark_name,
+        change_id,
+        if allow_backwards {
+            " --allow-backwards"
+        } else {
+            ""
+        }
     );
 
-    let output = Command::new("jj")
+    let mut command = Command::new("jj");
+    command
         .arg("bookmark")
-        .arg("create")
+        .arg("set")
         .arg(bookmark_name)
         .arg("--revision")
-        .arg(change_id)
+        .arg(change_id);
+    if allow_backwards {
+        command.arg("--allow-backwards");
+    }
+
+    let output = command
         .output()
-        .context("Failed to execute jj bookmark create")?;
+        .context("Failed to execute jj bookmark set")?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("jj bookmark create failed: {stderr}");
+        anyhow::bail!("jj bookmark set failed: {stderr}");
     }
 
     Ok(())
 }
 
-/// Push a bookmark to the remote
-pub fn push_bookmark(bookmark_name: &str) -> Result<()> {
+/// Push a bookmark to the remote, returns whether the remote bookmark was changed
+///
+/// jj only force-pushes if the remote bookmark is still where jj last saw it,
+/// so commits pushed by someone else are never overwritten.
+pub fn push_bookmark(bookmark_name: &str, remote: &str) -> Result<bool> {
     debug!(
-        "Executing command: jj git push --bookmark {} --allow-new",
-        bookmark_name
+        "Executing command: jj git push --remote {} --bookmark {} --allow-new",
+        remote, bookmark_name
     );
 
     let output = Command::new("jj")
         .arg("git")
         .arg("push")
+        .arg("--remote")
+        .arg(remote)
         .arg("--bookmark")
         .arg(bookmark_name)
         .arg("--allow-new")
@@ -119,16 +214,24 @@ pub fn push_bookmark(bookmark_name: &str) -> Result<()> {
         anyhow::bail!("jj git push failed: {stderr}");
     }
 
-    Ok(())
+    // jj reports "Nothing changed." when the remote bookmark already points at the commit
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(!stderr.contains("Nothing changed") && !stdout.contains("Nothing changed"))
 }
 
 /// Push a change and let jj create an automatic bookmark, returns the bookmark name
-pub fn push_change_auto_bookmark(change_id: &str) -> Result<String> {
-    debug!("Executing command: jj git push --change {}", change_id);
+pub fn push_change_auto_bookmark(change_id: &str, remote: &str) -> Result<String> {
+    debug!(
+        "Executing command: jj git push --remote {} --change {}",
+        remote, change_id
+    );
 
     let output = Command::new("jj")
         .arg("git")
         .arg("push")
+        .arg("--remote")
+        .arg(remote)
         .arg("--change")
         .arg(change_id)
         .output()
@@ -154,3 +257,423 @@ pub fn push_change_auto_bookmark(change_id: &str) -> Result<String> {
         "Failed to extract auto-generated bookmark name from jj git push output: {stdout}"
     )
 }
+
+/// Get the id of the most recent operation in the operation log
+pub fn current_operation_id() -> Result<String> {
+    debug!("Executing command: jj op log --no-graph --limit 1 --template id");
+
+    let output = Command::new("jj")
+        .arg("op")
+        .arg("log")
+        .arg("--no-graph")
+        .arg("--limit")
+        .arg("1")
+        .arg("--template")
+        .arg("id")
+        .output()
+        .context("Failed to execute jj op log")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj op log failed: {stderr}");
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.trim().to_string())
+}
+
+/// Restore the repository to the state it had after the given operation
+pub fn restore_operation(operation_id: &str) -> Result<()> {
+    debug!("Executing command: jj op restore {}", operation_id);
+
+    let output = Command::new("jj")
+        .arg("op")
+        .arg("restore")
+        .arg(operation_id)
+        .output()
+        .context("Failed to execute jj op restore")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj op restore failed: {stderr}");
+    }
+
+    Ok(())
+}
+
+/// Move a single revision onto a new destination, its descendants stay where they are
+pub fn rebase_revision(change_id: &str, destination: &str) -> Result<()> {
+    debug!(
+        "Executing command: jj rebase --revisions {} --destination {}",
+        change_id, destination
+    );
+
+    let output = Command::new("jj")
+        .arg("rebase")
+        .arg("--revisions")
+        .arg(change_id)
+        .arg("--destination")
+        .arg(destination)
+        .output()
+        .context("Failed to execute jj rebase")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj rebase failed: {stderr}");
+    }
+
+    Ok(())
+}
+
+/// Get the change IDs among the given changes that have unresolved conflicts
+pub fn conflicted_changes(change_ids: &[&str]) -> Result<Vec<String>> {
+    changes_matching(change_ids, "conflicts()")
+}
+
+/// Get the change IDs among the given changes that are part of trunk, e.g. after their PR
+/// was merged with a merge commit
+pub fn changes_in_trunk(change_ids: &[&str]) -> Result<Vec<String>> {
+    changes_matching(change_ids, "::trunk()")
+}
+
+/// Get the change IDs among the given changes that are also in `revset`
+fn changes_matching(change_ids: &[&str], revset: &str) -> Result<Vec<String>> {
+    let revisions = format!("({}) & {revset}", change_ids.join(" | "));
+    let template_arg = "change_id ++ \"\\n\"";
+
+    debug!(
+        "Executing command: jj log --no-graph --revisions {revisions} --template {template_arg}",
+    );
+
+    let output = Command::new("jj")
+        .arg("log")
+        .arg("--no-graph")
+        .arg("--revisions")
+        .arg(&revisions)
+        .arg("--template")
+        .arg(template_arg)
+        .output()
+        .context("Failed to execute jj log command")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj log failed: {stderr}");
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Move a change and all its descendants onto a new destination
+pub fn rebase_source(change_id: &str, destination: &str) -> Result<()> {
+    debug!(
+        "Executing command: jj rebase --source {} --destination {}",
+        change_id, destination
+    );
+
+    let output = Command::new("jj")
+        .arg("rebase")
+        .arg("--source")
+        .arg(change_id)
+        .arg("--destination")
+        .arg(destination)
+        .output()
+        .context("Failed to execute jj rebase")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj rebase failed: {stderr}");
+    }
+
+    Ok(())
+}
+
+/// Abandon changes, their descendants are rebased onto their parents
+pub fn abandon(change_ids: &[String]) -> Result<()> {
+    debug!("Executing command: jj abandon {}", change_ids.join(" "));
+
+    let output = Command::new("jj")
+        .arg("abandon")
+        .args(change_ids)
+        .output()
+        .context("Failed to execute jj abandon")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj abandon failed: {stderr}");
+    }
+
+    Ok(())
+}
+
+/// Delete a local bookmark, the next push of it deletes it on the remote too
+pub fn delete_bookmark(bookmark_name: &str) -> Result<()> {
+    debug!("Executing command: jj bookmark delete {}", bookmark_name);
+
+    let output = Command::new("jj")
+        .arg("bookmark")
+        .arg("delete")
+        .arg(bookmark_name)
+        .output()
+        .context("Failed to execute jj bookmark delete")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj bookmark delete failed: {stderr}");
+    }
+
+    Ok(())
+}
+
+/// Fetch from all git remotes
+pub fn fetch() -> Result<()> {
+    debug!("Executing command: jj git fetch --all-remotes");
+
+    let output = Command::new("jj")
+        .arg("git")
+        .arg("fetch")
+        .arg("--all-remotes")
+        .output()
+        .context("Failed to execute jj git fetch")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj git fetch failed: {stderr}");
+    }
+
+    Ok(())
+}
+
+/// Get the URL of a git remote of the repository
+pub fn remote_url(remote: &str) -> Result<String> {
+    debug!("Executing command: jj git remote list");
+
+    let output = Command::new("jj")
+        .arg("git")
+        .arg("remote")
+        .arg("list")
+        .output()
+        .context("Failed to execute jj git remote list")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj git remote list failed: {stderr}");
+    }
+
+    // Each line is "<name> <url>"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(name, _)| *name == remote)
+        .map(|(_, url)| url.trim().to_string())
+        .with_context(|| format!("The repository has no git remote named '{remote}'"))
+}
+
+/// Read a value from the jj config, `None` if it is not set
+pub fn config_value(name: &str) -> Result<Option<String>> {
+    debug!("Executing command: jj config get {name}");
+
+    let output = Command::new("jj")
+        .arg("config")
+        .arg("get")
+        .arg(name)
+        .output()
+        .context("Failed to execute jj config get")?;
+
+    // jj exits with an error for keys that are not set
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        debug!("jj config get {name} failed: {stderr}");
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let value = stdout.trim();
+    Ok((!value.is_empty()).then(|| value.to_string()))
+}
+
+/// Where a local bookmark and its copy on a remote point, by commit id
+#[derive(Debug, Clone, Default)]
+pub struct BookmarkTarget {
+    pub local: Option<String>,
+    pub remote: Option<String>,
+}
+
+/// Get the local and remote targets of every bookmark that exists locally or on `remote`
+pub fn bookmark_targets(remote: &str) -> Result<HashMap<String, BookmarkTarget>> {
+    // Conflicted bookmarks have no normal target and print an empty commit id
+    let template_arg = "name ++ \"\\t\" ++ if(remote, remote, \"\") ++ \"\\t\" ++ if(normal_target, normal_target.commit_id()) ++ \"\\n\"";
+
+    debug!("Executing command: jj bookmark list --all-remotes --template {template_arg}");
+
+    let output = Command::new("jj")
+        .arg("bookmark")
+        .arg("list")
+        .arg("--all-remotes")
+        .arg("--template")
+        .arg(template_arg)
+        .output()
+        .context("Failed to execute jj bookmark list")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj bookmark list failed: {stderr}");
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut targets: HashMap<String, BookmarkTarget> = HashMap::new();
+    for line in stdout.lines() {
+        let mut fields = line.split('\t');
+        let (Some(name), Some(bookmark_remote), Some(commit_id)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let commit_id = (!commit_id.is_empty()).then(|| commit_id.to_string());
+
+        if bookmark_remote.is_empty() {
+            targets.entry(name.to_string()).or_default().local = commit_id;
+        } else if bookmark_remote == remote {
+            targets.entry(name.to_string()).or_default().remote = commit_id;
+        }
+    }
+    Ok(targets)
+}
+
+/// Get the root directory of the current workspace
+pub fn workspace_root() -> Result<PathBuf> {
+    debug!("Executing command: jj root");
+
+    let output = Command::new("jj")
+        .arg("root")
+        .output()
+        .context("Failed to execute jj root")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj root failed: {stderr}");
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(PathBuf::from(stdout.trim()))
+}
+
+/// Get all local bookmarks with the change they point at, `None` for conflicted bookmarks
+pub fn local_bookmarks() -> Result<HashMap<String, Option<String>>> {
+    // Conflicted bookmarks have no normal target and print an empty change id
+    let template_arg =
+        "if(!remote, name ++ \"\\t\" ++ if(normal_target, normal_target.change_id()) ++ \"\\n\")";
+
+    debug!("Executing command: jj bookmark list --template {template_arg}");
+
+    let output = Command::new("jj")
+        .arg("bookmark")
+        .arg("list")
+        .arg("--template")
+        .arg(template_arg)
+        .output()
+        .context("Failed to execute jj bookmark list")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("jj bookmark list failed: {stderr}");
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, change_id) = line.split_once('\t')?;
+            let change_id = Some(change_id.trim()).filter(|id| !id.is_empty());
+            Some((name.to_string(), change_id.map(str::to_string)))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a change with the given description the way `jj log` prints it
+    fn change(description: &str) -> Change {
+        let line = serde_json::json!({
+            "change_id": "kxqpmnrs",
+            "commit_id": "0123abcd",
+            "description": description,
+            "local_bookmarks": [],
+            "remote_bookmarks": [],
+            "parents": ["zzzzzzzz"],
+            "author": {"name": "A", "email": "a@example.com"},
+            "empty": false,
+            "conflict": false,
+            "divergent": false,
+        });
+        parse_jj_log(&line.to_string()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn divergent_change_is_listed_once() {
+        let line = |commit_id: &str| {
+            serde_json::json!({
+                "change_id": "kxqpmnrs",
+                "commit_id": commit_id,
+                "description": "Add the parser\n",
+                "local_bookmarks": [],
+                "remote_bookmarks": [],
+                "parents": ["zzzzzzzz"],
+                "author": {"name": "A", "email": "a@example.com"},
+                "empty": false,
+                "conflict": false,
+                "divergent": true,
+            })
+            .to_string()
+        };
+        let changes =
+            parse_jj_log(&format!("{}\n{}\n", line("0123abcd"), line("4567ef01"))).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].commit_id, "0123abcd");
+    }
+
+    #[test]
+    fn title_and_body_of_a_multi_line_description() {
+        let change = change("Add the parser\n\nIt reads the stack file.\n\nSecond paragraph.\n");
+        assert_eq!(change.title(), "Add the parser");
+        assert_eq!(
+            change.body(),
+            "It reads the stack file.\n\nSecond paragraph."
+        );
+    }
+
+    #[test]
+    fn title_only_description_has_an_empty_body() {
+        let change = change("Add the parser\n");
+        assert_eq!(change.title(), "Add the parser");
+        assert_eq!(change.body(), "");
+    }
+
+    #[test]
+    fn empty_description_has_no_title_or_body() {
+        let change = change("");
+        assert_eq!(change.title(), "");
+        assert_eq!(change.body(), "");
+    }
+
+    #[test]
+    fn title_and_body_are_trimmed() {
+        let change = change("  Add the parser  \n\n\n  Body  \n\n");
+        assert_eq!(change.title(), "Add the parser");
+        assert_eq!(change.body(), "Body");
+    }
+
+    #[test]
+    fn body_keeps_lines_that_look_like_separators() {
+        let change = change("Title\n\nLine 1\n---\nLine 2\n");
+        assert_eq!(change.body(), "Line 1\n---\nLine 2");
+    }
+}
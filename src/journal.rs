@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::jj;
+
+/// The progress of a run, saved after every step so that a failed run can be resumed
+/// or rolled back. Lives in `.jj/stack-prs/` of the workspace, next to jj's own state.
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// The journal of the current jj workspace
+    pub fn open() -> Result<Self> {
+        let path = jj::workspace_root()?
+            .join(".jj")
+            .join("stack-prs")
+            .join("journal.json");
+        debug!("Using the journal at {}", path.display());
+        Ok(Journal { path })
+    }
+
+    /// The record of the last run that finished, kept so that it can be undone
+    pub fn last_run(&self) -> Journal {
+        Journal {
+            path: self.path.with_file_name("last-run.json"),
+        }
+    }
+
+    /// Read the saved progress, `None` if no run is unfinished
+    pub fn load<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read the journal {}", self.path.display()))
+            }
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Failed to parse the journal {}", self.path.display()))
+    }
+
+    /// Save the progress, replacing the file in one step so a crash never leaves half of it
+    pub fn save<T: Serialize>(&self, progress: &T) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(&self.path);
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(progress)?)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to write the journal {}", self.path.display()))
+    }
+
+    /// Forget the saved progress once a run has finished or was rolled back
+    pub fn remove(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to remove the journal {}", self.path.display())),
+        }
+    }
+}
@@ -1,23 +1,296 @@
+mod forge;
+mod gitea;
 mod github;
+mod gitlab;
+mod http;
 mod jj;
+mod journal;
+mod plan;
+mod remote;
 mod stack;
+mod status;
+mod sync;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bpaf::*;
+use log::debug;
 use owo_colors::OwoColorize;
+use std::io::IsTerminal;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
-struct Args {
+enum Args {
+    /// Edit the stack and create or update its PRs
+    Submit(SubmitArgs),
+    /// Show the PRs of the stack
+    Status(StatusArgs),
+    /// Clean up the stack after PRs at its bottom were merged
+    Sync(SyncArgs),
+    /// Revert the last finished run
+    Undo(UndoArgs),
+}
+
+#[derive(Debug, Clone)]
+struct SubmitArgs {
     revisions: String,
+    base_branch: Option<String>,
+    draft: bool,
+    stack_source: StackSource,
+    force: bool,
+    recovery: Option<Recovery>,
+    dry_run: bool,
+    fork_mode: bool,
+    sync: plan::SyncPolicy,
+    common: CommonArgs,
+}
+
+/// Where the stack of a run comes from
+#[derive(Debug, Clone)]
+enum StackSource {
+    Editor,
+    /// Every change with the default action, without asking
+    NoEdit,
+    File(PathBuf),
+}
+
+/// What to do about a run that failed part way, see `journal.rs`
+#[derive(Debug, Clone, Copy)]
+enum Recovery {
+    Resume,
+    Rollback,
+}
+
+#[derive(Debug, Clone)]
+struct StatusArgs {
+    revisions: String,
+    common: CommonArgs,
+}
+
+#[derive(Debug, Clone)]
+struct SyncArgs {
+    revisions: String,
+    base_branch: Option<String>,
+    dry_run: bool,
+    fork_mode: bool,
+    common: CommonArgs,
+}
+
+#[derive(Debug, Clone)]
+struct UndoArgs {
+    dry_run: bool,
+    common: CommonArgs,
+}
+
+/// Options every command takes
+#[derive(Debug, Clone)]
+struct CommonArgs {
+    forge: Option<forge::ForgeKind>,
+    github_backend: github::BackendKind,
+    host: Option<String>,
+    remote: Option<String>,
+    pr_repo: Option<String>,
+    api_url: Option<String>,
     verbose: usize,
 }
 
+impl Args {
+    fn common(&self) -> &CommonArgs {
+        match self {
+            Args::Submit(args) => &args.common,
+            Args::Status(args) => &args.common,
+            Args::Sync(args) => &args.common,
+            Args::Undo(args) => &args.common,
+        }
+    }
+}
+
 fn args() -> OptionParser<Args> {
-    let revisions = long("revisions")
+    let status = status_args()
+        .map(Args::Status)
+        .to_options()
+        .descr("Show every change of the stack with its bookmark, PR, review and CI status")
+        .command("status");
+
+    let sync = sync_args()
+        .map(Args::Sync)
+        .to_options()
+        .descr("Fetch, then drop the changes of merged PRs at the bottom of the stack, rebase the rest onto trunk,\n retarget the next PR, delete the merged bookmarks and refresh the stack comments")
+        .command("sync");
+
+    let undo = undo_args()
+        .map(Args::Undo)
+        .to_options()
+        .descr("Revert the last finished run: move or delete the branches it pushed, close the PRs it opened,\n restore the bases, titles, bodies and stack comments it changed and restore the jj operation it started from")
+        .command("undo");
+
+    let submit = submit_args().map(Args::Submit);
+
+    construct!([status, sync, undo, submit])
+        .to_options()
+        .descr("Create stacked PRs on GitHub, GitLab, Gitea or Forgejo using jj")
+}
+
+/// Revisions of the stack when `--revisions` is not given
+const DEFAULT_REVISIONS: &str = "trunk()::@";
+
+fn submit_args() -> impl Parser<SubmitArgs> {
+    let revisions = revisions(DEFAULT_REVISIONS);
+
+    let base_branch = base_branch();
+
+    let draft = long("draft")
+        .short('d')
+        .help("Use \"draft\" instead of \"pr\" as the default action for changes without a PR")
+        .switch();
+
+    let stack_file = long("stack-file")
+        .help("Read the stack from a file in the format of the stack file instead of opening the editor")
+        .argument::<PathBuf>("PATH")
+        .map(StackSource::File);
+
+    let no_edit = long("yes")
+        .short('y')
+        .long("no-edit")
+        .help("Skip the editor and use the stack file as it is offered, for scripts and CI")
+        .req_flag(StackSource::NoEdit);
+
+    let stack_source = construct!([stack_file, no_edit]).fallback(StackSource::Editor);
+
+    let force = long("force")
+        .short('f')
+        .help("Allow PRs for changes with unresolved conflicts")
+        .switch();
+
+    let resume = long("resume")
+        .help("Continue the previous run from the step that failed")
+        .req_flag(Recovery::Resume);
+
+    let rollback = long("rollback")
+        .help("Close the PRs and delete the bookmarks the previous, failed run created")
+        .req_flag(Recovery::Rollback);
+
+    let recovery = construct!([resume, rollback]).optional();
+
+    let dry_run = dry_run();
+
+    let fork_mode = fork_mode();
+
+    let sync = long("sync")
+        .help("What to do when an existing PR's title or body differs from the jj description:\n jj-wins (default), github-wins or ask")
+        .argument::<plan::SyncPolicy>("POLICY")
+        .fallback(plan::SyncPolicy::JjWins);
+
+    let common = common_args();
+
+    construct!(SubmitArgs {
+        revisions,
+        base_branch,
+        draft,
+        stack_source,
+        force,
+        recovery,
+        dry_run,
+        fork_mode,
+        sync,
+        common,
+    })
+}
+
+fn status_args() -> impl Parser<StatusArgs> {
+    // trunk() is drawn as the base branch on top, not as a change of the stack
+    let revisions = revisions("trunk()..@");
+    let common = common_args();
+
+    construct!(StatusArgs { revisions, common })
+}
+
+fn sync_args() -> impl Parser<SyncArgs> {
+    // Fetching moves trunk() past the stack when a PR was squash-merged,
+    // so the stack is no longer between trunk() and @
+    let revisions = revisions("trunk()..@");
+    let base_branch = base_branch();
+    let dry_run = dry_run();
+    let fork_mode = fork_mode();
+    let common = common_args();
+
+    construct!(SyncArgs {
+        revisions,
+        base_branch,
+        dry_run,
+        fork_mode,
+        common,
+    })
+}
+
+fn undo_args() -> impl Parser<UndoArgs> {
+    let dry_run = dry_run();
+    let common = common_args();
+
+    construct!(UndoArgs { dry_run, common })
+}
+
+fn revisions(default_revisions: &'static str) -> impl Parser<String> {
+    long("revisions")
         .short('r')
-        .help("Revision to consider for stack. Defaults to trunk()::@")
+        .help(format!("Revision to consider for stack. Defaults to {default_revisions}").as_str())
         .argument::<String>("REVISION")
-        .fallback("trunk()::@".to_string());
+        .fallback(default_revisions.to_string())
+}
+
+fn base_branch() -> impl Parser<Option<String>> {
+    long("base-branch")
+        .short('b')
+        .help("Branch the first PR of the stack targets. Defaults to the bookmark trunk() resolves to")
+        .argument::<String>("BRANCH")
+        .optional()
+}
+
+fn dry_run() -> impl Parser<bool> {
+    long("dry-run")
+        .short('n')
+        .help("Print the jj commands and PR updates that would run without running them")
+        .switch()
+}
+
+fn fork_mode() -> impl Parser<bool> {
+    long("fork-mode")
+        .help("Open every PR against the base branch and mark which commit to review.\n On by default when --pr-repo is not the repository of --remote")
+        .switch()
+}
+
+fn common_args() -> impl Parser<CommonArgs> {
+    let forge = long("forge")
+        .help("Forge hosting the repository: github, gitlab, gitea or forgejo.\n Defaults to the stack-prs.forge jj config, then gitlab for hosts named gitlab.*,\n gitea for codeberg.org and hosts named gitea.* or forgejo.*, and github otherwise")
+        .argument::<forge::ForgeKind>("FORGE")
+        .optional();
+
+    let github_backend = long("github-backend")
+        .env("STACK_PRS_GITHUB_BACKEND")
+        .help("How to talk to GitHub: gh (default) shells out to the GitHub CLI,\n native calls the API with GH_TOKEN, GITHUB_TOKEN or gh's stored token")
+        .argument::<github::BackendKind>("BACKEND")
+        .fallback(github::BackendKind::Gh);
+
+    let host = long("host")
+        .env("GH_HOST")
+        .help("Forge host, e.g. github.example.com for GitHub Enterprise Server.\n Defaults to the stack-prs.host jj config, then the host of the origin remote")
+        .argument::<String>("HOST")
+        .optional();
+
+    let remote = long("remote")
+        .help("Git remote to push bookmarks to.\n Defaults to jj's git.push config, then origin")
+        .argument::<String>("REMOTE")
+        .optional();
+
+    let pr_repo = long("pr-repo")
+        .env("GH_REPO")
+        .help("Repository to open PRs in, as a remote name or [HOST/]OWNER/NAME.\n Defaults to the stack-prs.pr-repo jj config, then the repository of --remote.\n PRs from another repository use OWNER:BRANCH heads")
+        .argument::<String>("REPO")
+        .optional();
+
+    let api_url = long("api-url")
+        .help("Base URL of the forge API, used by GitLab, Gitea and the native GitHub backend.\n Defaults to GITHUB_API_URL on GitHub, then the stack-prs.api-url jj config,\n then https://api.github.com, https://HOST/api/v3 on GitHub Enterprise Server,\n https://HOST/api/v4 on GitLab or https://HOST/api/v1 on Gitea and Forgejo")
+        .argument::<String>("URL")
+        .optional();
 
     let verbose = short('v')
         .long("verbose")
@@ -27,28 +300,324 @@ fn args() -> OptionParser<Args> {
         .map(|xs| xs.len())
         .guard(|&x| x <= 3, "It doesn't get any more verbose than this");
 
-    construct!(Args { revisions, verbose })
-        .to_options()
-        .descr("Create stacked PRs on GitHub using jj")
+    construct!(CommonArgs {
+        forge,
+        github_backend,
+        host,
+        remote,
+        pr_repo,
+        api_url,
+        verbose,
+    })
 }
 
 fn main() -> Result<()> {
     let args = args().run();
 
-    setup_logging(args.verbose)?;
+    setup_logging(args.common().verbose)?;
 
-    // Get all changes between base and target that are mine()
-    let changes = jj::get_changes(&args.revisions)?;
+    match args {
+        Args::Submit(args) => submit(args),
+        Args::Status(args) => status(args),
+        Args::Sync(args) => sync(args),
+        Args::Undo(args) => undo(args),
+    }
+}
 
-    // Create and edit the stack file
-    let stack_entries = stack::edit_stack(changes)?;
+fn submit(args: SubmitArgs) -> Result<()> {
+    let target = connect(&args.common)?;
+    let forge = target.forge.as_ref();
+    // PRs cannot target a branch of a fork, so a stack from a fork cannot be chained
+    let fork_mode = args.fork_mode || target.from_fork;
+    let journal = journal::Journal::open()?;
 
-    // Process the stack entries
-    process_stack(stack_entries)?;
+    match args.recovery {
+        Some(Recovery::Resume) => return plan::resume(forge, &journal, args.dry_run),
+        Some(Recovery::Rollback) => return plan::rollback(forge, &journal, args.dry_run),
+        None if !args.dry_run => plan::ensure_no_unfinished_run(&journal)?,
+        None => {}
+    }
+
+    if args.sync == plan::SyncPolicy::Ask && !args.dry_run && !std::io::stdin().is_terminal() {
+        anyhow::bail!("--sync ask needs a terminal, use --sync jj-wins or --sync github-wins");
+    }
+
+    let base_branch = resolve_base_branch(forge, args.base_branch)?;
+
+    // Get all changes between base and target that are mine(). Without the editor nobody
+    // gets to skip trunk() itself, which the default revisions include
+    let revisions = match args.stack_source {
+        StackSource::NoEdit => format!("({}) ~ trunk()", args.revisions),
+        _ => args.revisions.clone(),
+    };
+    let changes = jj::get_changes(&revisions)?;
+
+    // Create and edit the stack file, or take it as it is
+    let default_action = if args.draft {
+        stack::Action::Draft
+    } else {
+        stack::Action::CreatePr
+    };
+    // Changes with a PR are offered with its draft state rather than the default action
+    let bookmarks: Vec<&str> = changes
+        .iter()
+        .rev()
+        .filter_map(|change| change.bookmarks.first().map(String::as_str))
+        .collect();
+    let mut prs = forge::PrCache::default();
+    if !matches!(args.stack_source, StackSource::File(_)) {
+        prs.fetch(forge, &bookmarks)?;
+    }
+    let mut stack_entries = match &args.stack_source {
+        StackSource::Editor => {
+            stack::edit_stack(&changes, &base_branch, default_action, &prs, args.force)?
+        }
+        StackSource::NoEdit => stack::default_stack(&changes, default_action, &prs, args.force)?,
+        StackSource::File(path) => stack::read_stack_file(path, &changes, args.force)?,
+    };
+    stack::ask_missing_titles(&mut stack_entries)?;
+
+    // Make the commit graph match the order chosen in the editor
+    let rebases = stack::plan_rebases(&changes, &stack_entries);
+
+    // Work out everything that needs to happen, then either show or do it
+    let plan = plan::build(
+        forge,
+        rebases,
+        stack_entries,
+        &plan::Options {
+            trunk: &base_branch,
+            remote: &target.remote,
+            sync_policy: args.sync,
+            fork_mode,
+        },
+        prs,
+    )?;
+    if args.dry_run {
+        plan.print();
+    } else if plan.operations.is_empty() {
+        println!("Nothing to do");
+    } else {
+        plan.execute(forge, &journal)?;
+    }
 
     Ok(())
 }
 
+fn status(args: StatusArgs) -> Result<()> {
+    let target = connect(&args.common)?;
+    let base_branch = resolve_base_branch(target.forge.as_ref(), None)?;
+    let changes = jj::get_changes(&format!("({}) ~ trunk()", args.revisions))?;
+    status::show(
+        target.forge.as_ref(),
+        &changes,
+        &base_branch,
+        &target.remote,
+    )
+}
+
+fn sync(args: SyncArgs) -> Result<()> {
+    let target = connect(&args.common)?;
+    let forge = target.forge.as_ref();
+    let fork_mode = args.fork_mode || target.from_fork;
+    let journal = journal::Journal::open()?;
+    if !args.dry_run {
+        plan::ensure_no_unfinished_run(&journal)?;
+    }
+
+    // Read the stack before fetching, the fetch deletes the bookmarks of merged PRs
+    // whose branches were deleted on the forge
+    let changes = jj::get_changes(&args.revisions)?;
+    sync::ensure_linear(&changes)?;
+    if args.dry_run {
+        // The fetch moves trunk() and deletes bookmarks, a dry run must not change anything
+        println!(
+            "Not fetching in a dry run, planning against the remote bookmarks jj already knows"
+        );
+    } else {
+        println!("Fetching from all remotes");
+        jj::fetch()?;
+    }
+
+    let base_branch = resolve_base_branch(forge, args.base_branch)?;
+    let plan = sync::build(forge, &changes, &base_branch, &target.remote, fork_mode)?;
+    if args.dry_run {
+        plan.print();
+    } else if plan.operations.is_empty() {
+        println!("Nothing to do");
+    } else {
+        plan.execute(forge, &journal)?;
+    }
+
+    Ok(())
+}
+
+fn undo(args: UndoArgs) -> Result<()> {
+    let target = connect(&args.common)?;
+    let journal = journal::Journal::open()?;
+    plan::undo(target.forge.as_ref(), &journal, args.dry_run)
+}
+
+/// The forge a run talks to and the remote it pushes to
+struct Target {
+    forge: Box<dyn forge::Forge>,
+    remote: String,
+    /// Whether bookmarks are pushed to a fork of the repository PRs are opened in
+    from_fork: bool,
+}
+
+/// Work out the remote, repositories and forge from the options and jj config, and connect
+fn connect(args: &CommonArgs) -> Result<Target> {
+    let remote = match &args.remote {
+        Some(remote) => remote.clone(),
+        None => jj::config_value("git.push")?.unwrap_or_else(|| "origin".to_string()),
+    };
+    let (repository, push_repository) =
+        resolve_repositories(args.host.clone(), args.pr_repo.clone(), &remote)?;
+    // Branches pushed to a fork are named OWNER:BRANCH in the PR repository
+    let head_repository = (push_repository != repository).then_some(push_repository);
+    if let Some(head_repository) = &head_repository {
+        debug!("Opening PRs from {head_repository} in {repository}");
+    }
+    let forge_kind = match args.forge {
+        Some(kind) => kind,
+        None => match jj::config_value("stack-prs.forge")? {
+            Some(kind) => kind.parse().map_err(anyhow::Error::msg)?,
+            None => forge::ForgeKind::detect(&repository.host),
+        },
+    };
+    // GitHub Actions sets GITHUB_API_URL on every runner, whichever forge the repository is on
+    let env_api_url = match forge_kind {
+        forge::ForgeKind::GitHub => std::env::var("GITHUB_API_URL").ok(),
+        forge::ForgeKind::GitLab | forge::ForgeKind::Gitea => None,
+    };
+    let api_url = match args.api_url.clone().or(env_api_url) {
+        Some(api_url) => api_url,
+        None => jj::config_value("stack-prs.api-url")?
+            .unwrap_or_else(|| forge_kind.default_api_url(&repository.host)),
+    };
+    debug!("Using {forge_kind:?} repository {repository} with API URL {api_url}");
+    let from_fork = head_repository.is_some();
+    let forge = forge::connect(
+        forge_kind,
+        args.github_backend,
+        repository,
+        head_repository,
+        &api_url,
+    )?;
+
+    Ok(Target {
+        forge,
+        remote,
+        from_fork,
+    })
+}
+
+/// Figure out which branch the bottom of the stack should target.
+///
+/// An explicit `--base-branch` wins, then the bookmark `trunk()` resolves to,
+/// and finally the default branch of the repository on the forge. When `trunk()` has
+/// several bookmarks, the one named like the forge's default branch is used.
+fn resolve_base_branch(forge: &dyn forge::Forge, base_branch: Option<String>) -> Result<String> {
+    if let Some(base_branch) = base_branch {
+        return Ok(base_branch);
+    }
+
+    match jj::trunk_bookmarks() {
+        Ok(bookmarks) => match bookmarks.as_slice() {
+            [] => debug!("trunk() does not resolve to a bookmark"),
+            [bookmark] => {
+                debug!("Using trunk() bookmark '{bookmark}' as base branch");
+                return Ok(bookmark.clone());
+            }
+            _ => {
+                let default_branch = forge.default_branch().with_context(|| {
+                    format!(
+                        "trunk() has several bookmarks ({}), pass the base branch with --base-branch",
+                        bookmarks.join(", ")
+                    )
+                })?;
+                if !bookmarks.contains(&default_branch) {
+                    anyhow::bail!(
+                        "trunk() has several bookmarks ({}) and none is the default branch '{default_branch}', \
+                         pass the base branch with --base-branch",
+                        bookmarks.join(", ")
+                    );
+                }
+                debug!("Using the default branch '{default_branch}' among the trunk() bookmarks as base branch");
+                return Ok(default_branch);
+            }
+        },
+        Err(e) => debug!("Could not resolve trunk(): {e}"),
+    }
+
+    let default_branch = forge
+        .default_branch()
+        .context("Could not determine the base branch, pass it with --base-branch")?;
+    debug!("Using the repository's default branch '{default_branch}' as base branch");
+    Ok(default_branch)
+}
+
+/// Figure out which repository PRs are opened in, and which one their branches are pushed to.
+///
+/// Branches go to the repository of `remote`. PRs are opened in `--pr-repo`, then the
+/// `stack-prs.pr-repo` jj config, and otherwise in the repository of `remote` as well.
+/// `--pr-repo` is either the name of another remote or `[HOST/]OWNER/NAME`.
+/// `--host` or the `stack-prs.host` jj config replaces the host of both.
+fn resolve_repositories(
+    host: Option<String>,
+    pr_repo: Option<String>,
+    remote: &str,
+) -> Result<(remote::Repository, remote::Repository)> {
+    let host = match host {
+        Some(host) => Some(host),
+        None => jj::config_value("stack-prs.host")?,
+    };
+    let pr_repo = match pr_repo {
+        Some(pr_repo) => Some(pr_repo),
+        None => jj::config_value("stack-prs.pr-repo")?,
+    };
+
+    let from_remote = |name: &str| -> Result<remote::Repository> {
+        let url = jj::remote_url(name)?;
+        remote::Repository::from_remote_url(&url)
+            .with_context(|| format!("Could not parse the URL '{url}' of remote '{name}'"))
+    };
+    let push_repository = from_remote(remote);
+
+    let mut pr_repository = match pr_repo {
+        Some(name) if !name.contains('/') => from_remote(&name)?,
+        Some(slug) => {
+            let default_host = match (&host, &push_repository) {
+                (Some(host), _) => host.clone(),
+                (None, Ok(repository)) => repository.host.clone(),
+                (None, Err(_)) => "github.com".to_string(),
+            };
+            remote::Repository::from_slug(&slug, &default_host).with_context(|| {
+                format!("Invalid repository '{slug}', expected a remote name or [HOST/]OWNER/NAME")
+            })?
+        }
+        None => match &push_repository {
+            Ok(repository) => repository.clone(),
+            Err(e) => {
+                anyhow::bail!("Could not determine the repository, pass it with --pr-repo: {e:#}")
+            }
+        },
+    };
+
+    // Without a usable remote URL, assume the branches live next to the PRs
+    let mut push_repository = push_repository.unwrap_or_else(|e| {
+        debug!("Pushing to the PR repository: {e:#}");
+        pr_repository.clone()
+    });
+
+    if let Some(host) = host {
+        pr_repository.host = host.clone();
+        push_repository.host = host;
+    }
+    Ok((pr_repository, push_repository))
+}
+
 fn setup_logging(verbosity: usize) -> Result<(), anyhow::Error> {
     let mut base_config = fern::Dispatch::new().format(move |out, message, record| {
         let level = match record.level() {
@@ -77,122 +646,3 @@ fn setup_logging(verbosity: usize) -> Result<(), anyhow::Error> {
 
     Ok(())
 }
-
-struct ProcessedPr {
-    pr_url: String,
-    pr_title: String,
-}
-
-fn process_stack(entries: Vec<stack::StackEntry>) -> Result<()> {
-    let mut previous_branch: Option<String> = None;
-    let mut processed_prs: Vec<ProcessedPr> = Vec::new();
-
-    // Count total PRs to be created (excluding skips)
-    let total_prs = entries
-        .iter()
-        .filter(|e| matches!(e.action, stack::Action::CreatePr))
-        .count();
-
-    // First pass: Create/collect all PRs
-    for entry in entries {
-        match entry.action {
-            stack::Action::Skip => {
-                println!("Skipping change {}", entry.change_id);
-                continue;
-            }
-            stack::Action::CreatePr => {
-                let base_branch = previous_branch.as_deref().unwrap_or("main");
-
-                // Determine which bookmark to use and get PR URL + title
-                let (bookmark, pr_url, pr_title) = if let Some(bookmark_name) =
-                    entry.bookmark.as_ref()
-                {
-                    // User provided a bookmark name (either existing or new)
-                    // Check if PR already exists for this bookmark
-                    if github::pr_exists(bookmark_name)? {
-                        println!(
-                            "PR already exists for bookmark '{bookmark_name}', keeping in stack"
-                        );
-                        let (pr_url, pr_title) = github::get_pr_info(bookmark_name)?;
-                        (bookmark_name.clone(), pr_url, pr_title)
-                    } else {
-                        // Need to create PR - bookmark might already exist or need to be created
-                        // Try to push the bookmark first, which will work if it exists
-                        // If it doesn't exist, create it first
-                        match jj::push_bookmark(bookmark_name) {
-                            Ok(_) => {
-                                println!("Creating PR for bookmark '{bookmark_name}' against '{base_branch}'");
-                                let pr_url = github::create_pr(
-                                    bookmark_name,
-                                    base_branch,
-                                    &entry.description,
-                                )?;
-                                (bookmark_name.clone(), pr_url, entry.description.clone())
-                            }
-                            Err(_) => {
-                                // Bookmark doesn't exist, create it
-                                println!(
-                                    "Creating bookmark '{bookmark_name}' for change {}",
-                                    entry.change_id
-                                );
-                                jj::create_bookmark(&entry.change_id, bookmark_name)?;
-                                jj::push_bookmark(bookmark_name)?;
-                                println!("Creating PR for bookmark '{bookmark_name}' against '{base_branch}'");
-                                let pr_url = github::create_pr(
-                                    bookmark_name,
-                                    base_branch,
-                                    &entry.description,
-                                )?;
-                                (bookmark_name.clone(), pr_url, entry.description.clone())
-                            }
-                        }
-                    }
-                } else {
-                    // No bookmark provided, let jj create an automatic one
-                    println!(
-                        "No bookmark for change {}, creating automatic bookmark",
-                        entry.change_id
-                    );
-                    let auto_bookmark = jj::push_change_auto_bookmark(&entry.change_id)?;
-                    println!("Created automatic bookmark '{auto_bookmark}', creating PR against '{base_branch}'");
-                    let pr_url =
-                        github::create_pr(&auto_bookmark, base_branch, &entry.description)?;
-                    (auto_bookmark, pr_url, entry.description.clone())
-                };
-
-                processed_prs.push(ProcessedPr { pr_url, pr_title });
-                previous_branch = Some(bookmark);
-            }
-        }
-    }
-
-    // Second pass: Add stack comments to all PRs
-    for (index, pr) in processed_prs.iter().enumerate() {
-        let position = index + 1;
-        let mut comment = format!(
-            "## Stack Information\n\nThis PR is **{} of {}** in the stack.\n",
-            position, total_prs
-        );
-
-        if index > 0 {
-            let prev_pr = &processed_prs[index - 1];
-            comment.push_str(&format!(
-                "\n⬇️ Previous PR: [{}]({})\n",
-                prev_pr.pr_title, prev_pr.pr_url
-            ));
-        }
-
-        if index < processed_prs.len() - 1 {
-            let next_pr = &processed_prs[index + 1];
-            comment.push_str(&format!(
-                "\n⬆️ Next PR: [{}]({})\n",
-                next_pr.pr_title, next_pr.pr_url
-            ));
-        }
-
-        println!("Adding/updating stack comment on PR: {}", pr.pr_url);
-        github::add_or_update_stack_comment(&pr.pr_url, &comment)?;
-    }
-
-    Ok(())
-}
@@ -0,0 +1,1642 @@
+use anyhow::Result;
+use log::debug;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+
+use crate::forge;
+use crate::jj;
+use crate::journal::Journal;
+use crate::stack::{self, StackEntry};
+
+/// A branch name that may only be known once an earlier operation has run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Branch {
+    Named(String),
+    /// The bookmark jj generates when pushing the change with the given ID
+    Automatic(String),
+}
+
+impl fmt::Display for Branch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Branch::Named(name) => write!(f, "{name}"),
+            Branch::Automatic(change_id) => write!(f, "<automatic bookmark for {change_id}>"),
+        }
+    }
+}
+
+/// Which side wins when an existing PR's title or body differs from the jj description
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Update the forge from jj, except for bodies that were edited on the forge
+    JjWins,
+    /// Never touch the title or body of an existing PR
+    GithubWins,
+    /// Show each difference and ask whether to update the forge, when the plan runs
+    Ask,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jj-wins" => Ok(SyncPolicy::JjWins),
+            "github-wins" => Ok(SyncPolicy::GithubWins),
+            "ask" => Ok(SyncPolicy::Ask),
+            _ => Err(format!(
+                "unknown sync policy '{s}', expected one of: jj-wins, github-wins, ask"
+            )),
+        }
+    }
+}
+
+/// A single jj or gh action of a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Operation {
+    /// Rebase changes into the order of the stack file, all or nothing
+    Reorder {
+        rebases: Vec<stack::Rebase>,
+    },
+    SetBookmark {
+        change_id: String,
+        bookmark: String,
+    },
+    PushBookmark {
+        bookmark: String,
+        remote: String,
+    },
+    PushChange {
+        change_id: String,
+        remote: String,
+    },
+    CreatePr {
+        change_id: String,
+        head: Branch,
+        base: Branch,
+        title: String,
+        body: String,
+        draft: bool,
+    },
+    ChangeBase {
+        pr_url: String,
+        from: String,
+        to: Branch,
+    },
+    /// Mark a draft PR ready for review, or convert it back to a draft
+    SetDraft {
+        pr_url: String,
+        draft: bool,
+    },
+    UpdateTitle {
+        pr_url: String,
+        title: String,
+        /// Set when the user is asked first, see `SyncPolicy::Ask`
+        #[serde(default)]
+        confirm: Option<Confirmation>,
+    },
+    UpdateBody {
+        pr_url: String,
+        body: String,
+        #[serde(default)]
+        confirm: Option<Confirmation>,
+    },
+    UpsertStackComment {
+        change_id: String,
+        /// The stack comment the PR already has, if any
+        existing: Option<forge::Comment>,
+    },
+    /// Abandon the changes of merged PRs and move the rest of the stack onto trunk,
+    /// all or nothing
+    DropMerged {
+        abandon: Vec<String>,
+        /// Bottom of the changes left in the stack
+        rebase: Option<String>,
+    },
+    DeleteBookmark {
+        bookmark: String,
+    },
+    /// Push a deleted bookmark to delete it on the remote
+    DeleteRemoteBookmark {
+        bookmark: String,
+        remote: String,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Reorder { rebases } => {
+                let lines: Vec<String> = rebases
+                    .iter()
+                    .map(|r| {
+                        format!(
+                            "jj rebase --revisions {} --destination {}",
+                            r.change_id, r.destination
+                        )
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Operation::SetBookmark {
+                change_id,
+                bookmark,
+            } => write!(f, "jj bookmark set {bookmark} --revision {change_id}"),
+            Operation::PushBookmark { bookmark, remote } => {
+                write!(f, "jj git push --remote {remote} --bookmark {bookmark}")
+            }
+            Operation::PushChange { change_id, remote } => {
+                write!(f, "jj git push --remote {remote} --change {change_id}")
+            }
+            Operation::CreatePr {
+                head,
+                base,
+                title,
+                draft,
+                ..
+            } => write!(
+                f,
+                "create {}PR {head} -> {base} titled {title:?}",
+                if *draft { "draft " } else { "" }
+            ),
+            Operation::SetDraft {
+                pr_url,
+                draft: true,
+            } => {
+                write!(f, "convert PR {pr_url} to a draft")
+            }
+            Operation::SetDraft {
+                pr_url,
+                draft: false,
+            } => write!(f, "mark PR {pr_url} ready for review"),
+            Operation::ChangeBase { pr_url, from, to } => {
+                write!(f, "retarget PR {pr_url} from {from} to {to}")
+            }
+            Operation::UpdateTitle {
+                pr_url,
+                title,
+                confirm,
+            } => write!(
+                f,
+                "set the title of PR {pr_url} to {title:?}{}",
+                if confirm.is_some() {
+                    " (if confirmed)"
+                } else {
+                    ""
+                }
+            ),
+            Operation::UpdateBody {
+                pr_url,
+                body,
+                confirm,
+            } => write!(
+                f,
+                "set the body of PR {pr_url} to {} line(s) from the change description{}",
+                body.lines().count(),
+                if confirm.is_some() {
+                    " (if confirmed)"
+                } else {
+                    ""
+                }
+            ),
+            Operation::UpsertStackComment {
+                change_id,
+                existing: Some(comment),
+            } => write!(
+                f,
+                "update stack comment {} on the PR for {change_id}, if changed",
+                comment.id
+            ),
+            Operation::UpsertStackComment {
+                change_id,
+                existing: None,
+            } => write!(f, "add a stack comment to the PR for {change_id}"),
+            Operation::DropMerged { abandon, rebase } => {
+                let mut lines = Vec::new();
+                if !abandon.is_empty() {
+                    lines.push(format!("jj abandon {}", abandon.join(" ")));
+                }
+                if let Some(change_id) = rebase {
+                    lines.push(format!(
+                        "jj rebase --source {change_id} --destination {TRUNK_REVSET}"
+                    ));
+                }
+                write!(f, "{}", lines.join("\n"))
+            }
+            Operation::DeleteBookmark { bookmark } => write!(f, "jj bookmark delete {bookmark}"),
+            Operation::DeleteRemoteBookmark { bookmark, remote } => write!(
+                f,
+                "jj git push --remote {remote} --bookmark {bookmark} (deletes it on {remote})"
+            ),
+        }
+    }
+}
+
+/// An update of an existing PR that only happens if the user agrees when the plan runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Confirmation {
+    question: Question,
+    /// What the forge has now
+    current: String,
+    /// What jj has
+    proposed: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum Question {
+    UpdateTitle,
+    UpdateBody,
+    /// The body was edited on the forge since stack-prs wrote it
+    ReplaceEditedBody,
+}
+
+impl Confirmation {
+    /// Show the difference and ask whether to update the PR
+    fn ask(&self, forge_name: &str, label: &str) -> Result<bool> {
+        print_diff(label, forge_name, &self.current, &self.proposed);
+        let question = match self.question {
+            Question::UpdateTitle => format!("Update the title on {forge_name}?"),
+            Question::UpdateBody => format!("Update the body on {forge_name}?"),
+            Question::ReplaceEditedBody => {
+                format!("The body was edited on {forge_name}, replace it anyway?")
+            }
+        };
+        confirm(&question)
+    }
+}
+
+/// Where `stack-prs sync` moves the changes left after merged PRs
+const TRUNK_REVSET: &str = "trunk()";
+
+/// A PR that is part of the stack once the plan has run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackPr {
+    pub change_id: String,
+    pub head: Branch,
+    pub existing: Option<forge::PrInfo>,
+    /// Change of the PR this one builds on, `None` at the bottom of the stack
+    #[serde(default)]
+    pub parent: Option<String>,
+}
+
+/// Everything a run will do, in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plan {
+    pub operations: Vec<Operation>,
+    pub stack: Vec<StackPr>,
+    /// Every PR targets trunk because the branches live in a fork
+    pub fork_mode: bool,
+}
+
+/// Where the PRs of a stack go and how existing ones are treated
+pub struct Options<'a> {
+    /// Branch the bottom PR targets
+    pub trunk: &'a str,
+    /// Remote the bookmarks are pushed to
+    pub remote: &'a str,
+    pub sync_policy: SyncPolicy,
+    pub fork_mode: bool,
+}
+
+/// Work out the operations needed to turn the edited stack into PRs.
+///
+/// Every PR targets the PR of its parent entry, so a stack that branches becomes a tree
+/// of PRs. Entries come parents first.
+///
+/// In fork mode every PR targets trunk, since a PR cannot use a branch of a fork as its base.
+/// Each PR then also contains the commits of the PRs below it, which its body points out.
+///
+/// `prs` holds the PRs looked up to offer the stack file, only bookmarks it has not looked
+/// up yet are fetched.
+///
+/// Only reads from jj and the forge, so it is safe to call for a dry run.
+pub fn build(
+    forge: &dyn forge::Forge,
+    rebases: Vec<stack::Rebase>,
+    entries: Vec<StackEntry>,
+    options: &Options,
+    mut prs: forge::PrCache,
+) -> Result<Plan> {
+    let Options {
+        trunk,
+        remote,
+        sync_policy,
+        fork_mode,
+    } = *options;
+    let mut operations = Vec::new();
+    let mut stack: Vec<StackPr> = Vec::new();
+    // Head branch, title and number of PRs below it of every PR planned so far, by change ID
+    let mut planned: HashMap<String, (Branch, String, usize)> = HashMap::new();
+
+    if !rebases.is_empty() {
+        operations.push(Operation::Reorder { rebases });
+    }
+
+    // Look up the PRs of the bookmarks the stack file was not offered with at once,
+    // such as ones typed in the editor
+    let bookmarks: Vec<&str> = entries
+        .iter()
+        .filter(|e| e.action != stack::Action::Skip)
+        .filter_map(|e| e.bookmark.as_deref())
+        .collect();
+    prs.fetch(forge, &bookmarks)?;
+
+    for entry in entries {
+        match entry.action {
+            stack::Action::Skip => {
+                println!("Skipping change {}", entry.change_id);
+                continue;
+            }
+            stack::Action::CreatePr | stack::Action::Draft => {
+                let draft = entry.action == stack::Action::Draft;
+                let parent = entry.parent.as_ref().and_then(|parent| planned.get(parent));
+                let base_branch = match parent {
+                    Some((parent_head, _, _)) if !fork_mode => parent_head.clone(),
+                    _ => Branch::Named(trunk.to_string()),
+                };
+                let depth = parent.map_or(0, |(_, _, parent_depth)| parent_depth + 1);
+                let body = match parent {
+                    Some((_, parent_title, _)) if fork_mode => {
+                        fork_body(depth, parent_title, &entry.description, &entry.body)
+                    }
+                    _ => entry.body.clone(),
+                };
+
+                let (head, existing) = if let Some(bookmark_name) = entry.bookmark {
+                    // Move the bookmark to the current commit of the change and push it,
+                    // so existing PRs pick up amended changes.
+                    operations.push(Operation::SetBookmark {
+                        change_id: entry.change_id.clone(),
+                        bookmark: bookmark_name.clone(),
+                    });
+                    operations.push(Operation::PushBookmark {
+                        bookmark: bookmark_name.clone(),
+                        remote: remote.to_string(),
+                    });
+
+                    let existing = prs.get(&bookmark_name).cloned();
+                    if let Some(pr) = &existing {
+                        debug!("Found PR #{} for bookmark '{bookmark_name}'", pr.number);
+                    }
+
+                    (Branch::Named(bookmark_name), existing)
+                } else {
+                    // No bookmark provided, let jj create an automatic one
+                    operations.push(Operation::PushChange {
+                        change_id: entry.change_id.clone(),
+                        remote: remote.to_string(),
+                    });
+                    (Branch::Automatic(entry.change_id.clone()), None)
+                };
+
+                match &existing {
+                    Some(pr) => {
+                        // The stack may have been reordered or a PR below skipped
+                        if base_branch != Branch::Named(pr.base.clone()) {
+                            operations.push(Operation::ChangeBase {
+                                pr_url: pr.url.clone(),
+                                from: pr.base.clone(),
+                                to: base_branch,
+                            });
+                        }
+
+                        if pr.is_draft != draft {
+                            operations.push(Operation::SetDraft {
+                                pr_url: pr.url.clone(),
+                                draft,
+                            });
+                        }
+
+                        sync_title_and_body(
+                            pr,
+                            &entry.description,
+                            &body,
+                            sync_policy,
+                            forge.name(),
+                            &mut operations,
+                        );
+                    }
+                    None => operations.push(Operation::CreatePr {
+                        change_id: entry.change_id.clone(),
+                        head: head.clone(),
+                        base: base_branch,
+                        body: render_body(&body),
+                        title: entry.description.clone(),
+                        draft,
+                    }),
+                }
+
+                planned.insert(
+                    entry.change_id.clone(),
+                    (head.clone(), entry.description, depth),
+                );
+                stack.push(StackPr {
+                    change_id: entry.change_id,
+                    head,
+                    existing,
+                    parent: entry.parent,
+                });
+            }
+        }
+    }
+
+    for pr in &stack {
+        operations.push(Operation::UpsertStackComment {
+            change_id: pr.change_id.clone(),
+            existing: pr
+                .existing
+                .as_ref()
+                .and_then(|existing| existing.stack_comment.clone()),
+        });
+    }
+
+    Ok(Plan {
+        operations,
+        stack,
+        fork_mode,
+    })
+}
+
+impl Plan {
+    /// Print every operation without running any of them
+    pub fn print(&self) {
+        if self.operations.is_empty() {
+            println!("Nothing to do");
+            return;
+        }
+
+        println!("The following operations would run:");
+        self.print_operations(0);
+    }
+
+    /// Print the operations from `start` on, numbered by their position in the whole plan
+    fn print_operations(&self, start: usize) {
+        for (index, operation) in self.operations.iter().enumerate().skip(start) {
+            for (line_index, line) in operation.to_string().lines().enumerate() {
+                if line_index == 0 {
+                    println!("{:>3}. {line}", index + 1);
+                } else {
+                    println!("     {line}");
+                }
+            }
+        }
+    }
+
+    /// Run every operation in order, saving the progress to the journal after each one
+    pub fn execute(&self, forge: &dyn forge::Forge, journal: &Journal) -> Result<()> {
+        ensure_no_unfinished_run(journal)?;
+
+        let progress = Progress {
+            plan: self.clone(),
+            completed: 0,
+            run: Run {
+                fork_mode: self.fork_mode,
+                start_operation: Some(jj::current_operation_id()?),
+                ..Run::default()
+            },
+        };
+        progress.run(forge, journal)
+    }
+
+    fn print_summary(&self, run: &Run) {
+        let updated_prs: Vec<&PrLink> = self
+            .stack
+            .iter()
+            .filter(|pr| pr.existing.is_some())
+            .filter(|pr| matches!(&pr.head, Branch::Named(b) if run.pushed.contains(b)))
+            .filter_map(|pr| run.prs.get(&pr.change_id))
+            .collect();
+
+        if updated_prs.is_empty() {
+            println!("\nNo existing PR received new commits");
+        } else {
+            println!("\nExisting PRs that received new commits:");
+            for pr in updated_prs {
+                println!("  {} ({})", pr.title, pr.url);
+            }
+        }
+
+        if !run.base_changes.is_empty() {
+            print_base_changes(&run.base_changes);
+        }
+    }
+}
+
+/// Refuse to start a run while the journal holds one that failed part way
+pub fn ensure_no_unfinished_run(journal: &Journal) -> Result<()> {
+    if journal.load::<Progress>()?.is_some() {
+        anyhow::bail!(
+            "A previous run did not finish. Continue it with `stack-prs --resume`, \
+             or undo what it created with `stack-prs --rollback`"
+        );
+    }
+    Ok(())
+}
+
+/// Continue the unfinished run saved in the journal from the operation that failed.
+/// With `dry_run`, only print the operations that are left.
+pub fn resume(forge: &dyn forge::Forge, journal: &Journal, dry_run: bool) -> Result<()> {
+    let progress: Progress = journal
+        .load()?
+        .ok_or_else(|| anyhow::anyhow!("There is no unfinished run to resume"))?;
+
+    if dry_run {
+        println!("Resuming the previous run would run the following operations:");
+        progress.plan.print_operations(progress.completed);
+        return Ok(());
+    }
+
+    println!(
+        "Resuming the previous run at step {} of {}",
+        progress.completed + 1,
+        progress.plan.operations.len()
+    );
+    progress.run(forge, journal)
+}
+
+/// Undo what the unfinished run saved in the journal created: close its new PRs and
+/// delete its new bookmarks, locally and on the remote.
+///
+/// Bookmarks that existed before the run, and rebases, are left as they are.
+/// With `dry_run`, only print what would be closed and deleted.
+pub fn rollback(forge: &dyn forge::Forge, journal: &Journal, dry_run: bool) -> Result<()> {
+    let progress: Progress = journal
+        .load()?
+        .ok_or_else(|| anyhow::anyhow!("There is no unfinished run to roll back"))?;
+    let run = progress.run;
+
+    if dry_run {
+        for side_effect in &run.side_effects {
+            if let SideEffect::CreatedPr { pr_url } = side_effect {
+                println!("Close PR {pr_url}");
+            }
+        }
+        for bookmark in &run.created_bookmarks {
+            match &bookmark.remote {
+                Some(remote) => println!(
+                    "Delete bookmark '{}', locally and on {remote}",
+                    bookmark.name
+                ),
+                None => println!("Delete bookmark '{}'", bookmark.name),
+            }
+        }
+        return Ok(());
+    }
+
+    let mut failures = Vec::new();
+    let created_prs = run.side_effects.iter().filter_map(|effect| match effect {
+        SideEffect::CreatedPr { pr_url } => Some(pr_url),
+        _ => None,
+    });
+    for pr_url in created_prs {
+        println!("Closing PR {pr_url}");
+        if let Err(e) = forge.close_pr(pr_url) {
+            failures.push(format!("{e:#}"));
+        }
+    }
+    for bookmark in &run.created_bookmarks {
+        println!("Deleting bookmark '{}'", bookmark.name);
+        let mut result = jj::delete_bookmark(&bookmark.name);
+        if let (Ok(()), Some(remote)) = (&result, &bookmark.remote) {
+            println!("Deleting bookmark '{}' on {remote}", bookmark.name);
+            result = jj::push_bookmark(&bookmark.name, remote).map(|_| ());
+        }
+        if let Err(e) = result {
+            failures.push(format!("{e:#}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "Rolling back the previous run failed, the journal was kept:\n  {}",
+            failures.join("\n  ")
+        );
+    }
+
+    journal.remove()?;
+    println!("Rolled back the previous run");
+    Ok(())
+}
+
+/// Revert the last finished run, newest change first: move the branches it pushed back
+/// or delete them, close its new PRs, restore what it changed on existing PRs and finally
+/// restore the jj repository to the operation the run started from.
+///
+/// Every reverted change is saved right away, so running it again after a failure
+/// continues where it stopped.
+///
+/// Restoring the repository throws away every jj operation after the run, so `undo` asks
+/// first when there are any, and refuses without a terminal to ask on.
+pub fn undo(forge: &dyn forge::Forge, journal: &Journal, dry_run: bool) -> Result<()> {
+    ensure_no_unfinished_run(journal)?;
+    let last_run = journal.last_run();
+    let mut progress: Progress = last_run
+        .load()?
+        .ok_or_else(|| anyhow::anyhow!("There is no finished run to undo"))?;
+    let start_operation = progress.run.start_operation.clone().ok_or_else(|| {
+        anyhow::anyhow!("The last run did not record the jj operation it started from")
+    })?;
+
+    let current_operation = jj::current_operation_id()?;
+    let later_work = progress
+        .run
+        .end_operation
+        .as_ref()
+        .is_some_and(|end_operation| *end_operation != current_operation);
+    let warning = format!(
+        "The repository changed since the last run. Restoring it to operation {start_operation} \
+         throws away those changes too, see `jj op log`"
+    );
+
+    if dry_run {
+        if later_work {
+            println!("{warning}");
+        }
+        for side_effect in progress.run.side_effects.iter().rev() {
+            println!("{side_effect}");
+        }
+        println!("Restore the repository to operation {start_operation}");
+        return Ok(());
+    }
+
+    if later_work {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("{warning}. Run `stack-prs undo` in a terminal to confirm");
+        }
+        println!("{warning}");
+        if !confirm("Undo the last run anyway?")? {
+            anyhow::bail!("Nothing was undone");
+        }
+    }
+
+    while let Some(side_effect) = progress.run.side_effects.last() {
+        println!("{side_effect}");
+        side_effect.revert(forge).map_err(|e| {
+            e.context(
+                "Undoing the last run failed. Run `stack-prs undo` again once the problem is fixed",
+            )
+        })?;
+        progress.run.side_effects.pop();
+        // Reverting can run jj itself, which is not later work to warn about next time
+        progress.run.end_operation = Some(jj::current_operation_id()?);
+        last_run.save(&progress)?;
+    }
+
+    println!("Restoring the repository to operation {start_operation}");
+    jj::restore_operation(&start_operation)?;
+    last_run.remove()?;
+    println!("Undid the last run");
+    Ok(())
+}
+
+/// What the journal holds: the plan of a run and how far it got
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    plan: Plan,
+    /// Number of operations that finished
+    completed: usize,
+    run: Run,
+}
+
+impl Progress {
+    /// Run the remaining operations, saving after each one. The journal is removed
+    /// once all of them finished, and kept when one fails.
+    fn run(mut self, forge: &dyn forge::Forge, journal: &Journal) -> Result<()> {
+        journal.save(&self)?;
+
+        while let Some(operation) = self.plan.operations.get(self.completed) {
+            let result = self.run.apply(forge, operation, &self.plan.stack);
+            if let Err(e) = result {
+                // Save what the failed operation already did, e.g. a bookmark it created
+                journal.save(&self)?;
+                return Err(e.context(format!(
+                    "Step {} of {} failed. Continue with `stack-prs --resume` once the problem is fixed, \
+                     or undo what this run created with `stack-prs --rollback`",
+                    self.completed + 1,
+                    self.plan.operations.len()
+                )));
+            }
+            self.completed += 1;
+            journal.save(&self)?;
+        }
+
+        // Keep the finished run around for `stack-prs undo`, unless it changed nothing and
+        // the run before it is still the one to undo
+        if !self.run.side_effects.is_empty() {
+            self.run.end_operation = Some(jj::current_operation_id()?);
+            journal.last_run().save(&self)?;
+        }
+        journal.remove()?;
+        self.plan.print_summary(&self.run);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PrLink {
+    url: String,
+    title: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BaseChange {
+    pr_url: String,
+    before: String,
+    after: String,
+}
+
+/// A bookmark a run created, and the remote it pushed it to
+#[derive(Serialize, Deserialize)]
+struct CreatedBookmark {
+    name: String,
+    remote: Option<String>,
+}
+
+/// A change a run made outside the jj repository, with what is needed to revert it
+#[derive(Serialize, Deserialize)]
+enum SideEffect {
+    /// A branch on the remote was created, moved or deleted. `before` is the commit it
+    /// pointed at, `None` if it did not exist.
+    Push {
+        bookmark: String,
+        remote: String,
+        before: Option<String>,
+    },
+    CreatedPr {
+        pr_url: String,
+    },
+    ChangedBase {
+        pr_url: String,
+        before: String,
+    },
+    ChangedDraft {
+        pr_url: String,
+        before: bool,
+    },
+    ChangedTitle {
+        pr_url: String,
+        before: String,
+    },
+    ChangedBody {
+        pr_url: String,
+        before: String,
+    },
+    AddedComment {
+        pr_url: String,
+        comment_id: String,
+    },
+    UpdatedComment {
+        pr_url: String,
+        comment_id: String,
+        before: String,
+    },
+}
+
+impl fmt::Display for SideEffect {
+    /// Describe how the side effect is reverted
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SideEffect::Push {
+                bookmark,
+                remote,
+                before: None,
+            } => write!(f, "Delete bookmark '{bookmark}' locally and on {remote}"),
+            SideEffect::Push {
+                bookmark,
+                remote,
+                before: Some(commit),
+            } => write!(f, "Move bookmark '{bookmark}' back to {commit} on {remote}"),
+            SideEffect::CreatedPr { pr_url } => write!(f, "Close PR {pr_url}"),
+            SideEffect::ChangedBase { pr_url, before } => {
+                write!(f, "Retarget PR {pr_url} back to '{before}'")
+            }
+            SideEffect::ChangedDraft {
+                pr_url,
+                before: true,
+            } => write!(f, "Convert PR {pr_url} back to a draft"),
+            SideEffect::ChangedDraft {
+                pr_url,
+                before: false,
+            } => write!(f, "Mark PR {pr_url} as ready for review again"),
+            SideEffect::ChangedTitle { pr_url, before } => {
+                write!(f, "Restore the title of PR {pr_url} to {before:?}")
+            }
+            SideEffect::ChangedBody { pr_url, .. } => {
+                write!(f, "Restore the previous body of PR {pr_url}")
+            }
+            SideEffect::AddedComment { pr_url, .. } => {
+                write!(f, "Delete the stack comment on PR {pr_url}")
+            }
+            SideEffect::UpdatedComment { pr_url, .. } => {
+                write!(f, "Restore the previous stack comment on PR {pr_url}")
+            }
+        }
+    }
+}
+
+impl SideEffect {
+    fn revert(&self, forge: &dyn forge::Forge) -> Result<()> {
+        match self {
+            SideEffect::Push {
+                bookmark,
+                remote,
+                before,
+            } => {
+                match before {
+                    Some(commit) => jj::set_bookmark(commit, bookmark, true)?,
+                    None => jj::delete_bookmark(bookmark)?,
+                }
+                jj::push_bookmark(bookmark, remote)?;
+            }
+            SideEffect::CreatedPr { pr_url } => forge.close_pr(pr_url)?,
+            SideEffect::ChangedBase { pr_url, before } => forge.edit_pr_base(pr_url, before)?,
+            SideEffect::ChangedDraft { pr_url, before } => forge.set_pr_draft(pr_url, *before)?,
+            SideEffect::ChangedTitle { pr_url, before } => forge.edit_pr_title(pr_url, before)?,
+            SideEffect::ChangedBody { pr_url, before } => forge.edit_pr_body(pr_url, before)?,
+            SideEffect::AddedComment { pr_url, comment_id } => {
+                forge.delete_pr_comment(pr_url, comment_id)?
+            }
+            SideEffect::UpdatedComment {
+                pr_url,
+                comment_id,
+                before,
+            } => forge.update_pr_comment(pr_url, comment_id, before)?,
+        }
+        Ok(())
+    }
+}
+
+/// What has happened so far while executing a plan
+#[derive(Default, Serialize, Deserialize)]
+struct Run {
+    /// Automatic bookmarks jj created, by change ID
+    bookmarks: HashMap<String, String>,
+    /// Bookmarks whose remote was changed by a push
+    pushed: HashSet<String>,
+    /// PRs of the stack, by change ID
+    prs: HashMap<String, PrLink>,
+    base_changes: Vec<BaseChange>,
+    fork_mode: bool,
+    /// Bookmarks that did not exist before the run
+    created_bookmarks: Vec<CreatedBookmark>,
+    /// The jj operation the run started from
+    start_operation: Option<String>,
+    /// The jj operation the run ended at, `undo` refuses to restore past later operations
+    #[serde(default)]
+    end_operation: Option<String>,
+    /// Changes to remotes and the forge, in the order they were made
+    side_effects: Vec<SideEffect>,
+    /// Local bookmarks before the run, loaded on first use
+    #[serde(skip)]
+    local_bookmarks: Option<HashMap<String, Option<String>>>,
+}
+
+impl Run {
+    fn branch_name(&self, branch: &Branch) -> Result<String> {
+        match branch {
+            Branch::Named(name) => Ok(name.clone()),
+            Branch::Automatic(change_id) => {
+                self.bookmarks.get(change_id).cloned().ok_or_else(|| {
+                    anyhow::anyhow!("No automatic bookmark was created for change {change_id}")
+                })
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        forge: &dyn forge::Forge,
+        operation: &Operation,
+        stack: &[StackPr],
+    ) -> Result<()> {
+        match operation {
+            Operation::Reorder { rebases } => apply_rebases(rebases)?,
+            Operation::SetBookmark {
+                change_id,
+                bookmark,
+            } => {
+                let local_bookmarks = match &mut self.local_bookmarks {
+                    Some(local_bookmarks) => local_bookmarks,
+                    none => none.insert(jj::local_bookmarks()?),
+                };
+                let is_new = local_bookmarks
+                    .insert(bookmark.clone(), Some(change_id.clone()))
+                    .is_none();
+                jj::set_bookmark(change_id, bookmark, false)?;
+                if is_new {
+                    self.created_bookmarks.push(CreatedBookmark {
+                        name: bookmark.clone(),
+                        remote: None,
+                    });
+                }
+            }
+            Operation::PushBookmark { bookmark, remote } => {
+                let before = remote_commit(bookmark, remote)?;
+                let pushed = jj::push_bookmark(bookmark, remote)?;
+
+                // A new local bookmark may still name a branch that already exists on the
+                // remote, rolling back must not delete that one
+                if before.is_none() {
+                    let created = self
+                        .created_bookmarks
+                        .iter_mut()
+                        .find(|created| created.name == *bookmark);
+                    if let Some(created) = created {
+                        created.remote = Some(remote.clone());
+                    }
+                }
+                if pushed {
+                    println!("Pushed new commits to bookmark '{bookmark}'");
+                    self.pushed.insert(bookmark.clone());
+                    self.side_effects.push(SideEffect::Push {
+                        bookmark: bookmark.clone(),
+                        remote: remote.clone(),
+                        before,
+                    });
+                }
+            }
+            Operation::PushChange { change_id, remote } => {
+                println!("No bookmark for change {change_id}, creating automatic bookmark");
+                let bookmark = jj::push_change_auto_bookmark(change_id, remote)?;
+                println!("Created automatic bookmark '{bookmark}'");
+                self.created_bookmarks.push(CreatedBookmark {
+                    name: bookmark.clone(),
+                    remote: Some(remote.clone()),
+                });
+                self.side_effects.push(SideEffect::Push {
+                    bookmark: bookmark.clone(),
+                    remote: remote.clone(),
+                    before: None,
+                });
+                self.bookmarks.insert(change_id.clone(), bookmark);
+            }
+            Operation::CreatePr {
+                change_id,
+                head,
+                base,
+                title,
+                body,
+                draft,
+            } => {
+                let head = self.branch_name(head)?;
+                let base = self.branch_name(base)?;
+                if *draft {
+                    println!("Creating draft PR for bookmark '{head}' against '{base}'");
+                } else {
+                    println!("Creating PR for bookmark '{head}' against '{base}'");
+                }
+                let url = forge.create_pr(&head, &base, title, body, *draft)?;
+                self.side_effects.push(SideEffect::CreatedPr {
+                    pr_url: url.clone(),
+                });
+                self.prs.insert(
+                    change_id.clone(),
+                    PrLink {
+                        url,
+                        title: title.clone(),
+                    },
+                );
+            }
+            Operation::ChangeBase { pr_url, from, to } => {
+                let to = self.branch_name(to)?;
+                println!("Retargeting PR {pr_url} from '{from}' to '{to}'");
+                forge.edit_pr_base(pr_url, &to)?;
+                self.side_effects.push(SideEffect::ChangedBase {
+                    pr_url: pr_url.clone(),
+                    before: from.clone(),
+                });
+                self.base_changes.push(BaseChange {
+                    pr_url: pr_url.clone(),
+                    before: from.clone(),
+                    after: to,
+                });
+            }
+            Operation::SetDraft { pr_url, draft } => {
+                if *draft {
+                    println!("Converting PR {pr_url} to a draft");
+                } else {
+                    println!("Marking PR {pr_url} as ready for review");
+                }
+                forge.set_pr_draft(pr_url, *draft)?;
+                self.side_effects.push(SideEffect::ChangedDraft {
+                    pr_url: pr_url.clone(),
+                    before: !draft,
+                });
+            }
+            Operation::UpdateTitle {
+                pr_url,
+                title,
+                confirm,
+            } => {
+                if let Some(confirm) = confirm {
+                    if !confirm.ask(forge.name(), &format!("title of {pr_url}"))? {
+                        println!("Leaving the title of PR {pr_url} as it is");
+                        return Ok(());
+                    }
+                }
+                println!("Updating the title of PR {pr_url} to {title:?}");
+                forge.edit_pr_title(pr_url, title)?;
+                if let Some(existing) = existing_pr(stack, pr_url) {
+                    self.side_effects.push(SideEffect::ChangedTitle {
+                        pr_url: pr_url.clone(),
+                        before: existing.title.clone(),
+                    });
+                }
+            }
+            Operation::UpdateBody {
+                pr_url,
+                body,
+                confirm,
+            } => {
+                if let Some(confirm) = confirm {
+                    if !confirm.ask(forge.name(), &format!("body of {pr_url}"))? {
+                        println!("Leaving the body of PR {pr_url} as it is");
+                        return Ok(());
+                    }
+                }
+                println!("Updating the body of PR {pr_url} from the change description");
+                forge.edit_pr_body(pr_url, body)?;
+                if let Some(existing) = existing_pr(stack, pr_url) {
+                    self.side_effects.push(SideEffect::ChangedBody {
+                        pr_url: pr_url.clone(),
+                        before: existing.body.clone(),
+                    });
+                }
+            }
+            Operation::DropMerged { abandon, rebase } => drop_merged(abandon, rebase.as_deref())?,
+            Operation::DeleteBookmark { bookmark } => {
+                println!("Deleting bookmark '{bookmark}'");
+                jj::delete_bookmark(bookmark)?;
+            }
+            Operation::DeleteRemoteBookmark { bookmark, remote } => {
+                let before = remote_commit(bookmark, remote)?;
+                if jj::push_bookmark(bookmark, remote)? {
+                    println!("Deleted bookmark '{bookmark}' on {remote}");
+                    self.side_effects.push(SideEffect::Push {
+                        bookmark: bookmark.clone(),
+                        remote: remote.clone(),
+                        before,
+                    });
+                }
+            }
+            Operation::UpsertStackComment {
+                change_id,
+                existing,
+            } => {
+                self.collect_existing_prs(stack)?;
+                let links: Vec<&PrLink> = stack.iter().map(|pr| &self.prs[&pr.change_id]).collect();
+                let index = stack
+                    .iter()
+                    .position(|pr| pr.change_id == *change_id)
+                    .ok_or_else(|| anyhow::anyhow!("Change {change_id} is not in the stack"))?;
+
+                let parents: Vec<Option<usize>> = stack
+                    .iter()
+                    .map(|pr| {
+                        let parent = pr.parent.as_ref()?;
+                        stack.iter().position(|other| other.change_id == *parent)
+                    })
+                    .collect();
+                let linear = parents
+                    .iter()
+                    .enumerate()
+                    .all(|(position, parent)| *parent == position.checked_sub(1));
+
+                let comment = if !linear {
+                    tree_stack_comment(index, &links, &parents, self.fork_mode)
+                } else if self.fork_mode {
+                    fork_stack_comment(index, &links)
+                } else {
+                    stack_comment(index, &links)
+                };
+                let pr_url = &links[index].url;
+                match existing {
+                    Some(existing) if existing.body.replace('\r', "") == comment => {
+                        debug!("Stack comment on PR {pr_url} is up to date");
+                    }
+                    Some(existing) => {
+                        println!("Updating stack comment on PR: {pr_url}");
+                        forge.update_pr_comment(pr_url, &existing.id, &comment)?;
+                        self.side_effects.push(SideEffect::UpdatedComment {
+                            pr_url: pr_url.clone(),
+                            comment_id: existing.id.clone(),
+                            before: existing.body.clone(),
+                        });
+                    }
+                    None => {
+                        println!("Adding stack comment to PR: {pr_url}");
+                        let comment_id = forge.add_pr_comment(pr_url, &comment)?;
+                        self.side_effects.push(SideEffect::AddedComment {
+                            pr_url: pr_url.clone(),
+                            comment_id,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make sure every PR of the stack has a link, either created in this run or already existing
+    fn collect_existing_prs(&mut self, stack: &[StackPr]) -> Result<()> {
+        for pr in stack {
+            if self.prs.contains_key(&pr.change_id) {
+                continue;
+            }
+            let existing = pr
+                .existing
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("No PR was created for change {}", pr.change_id))?;
+            self.prs.insert(
+                pr.change_id.clone(),
+                PrLink {
+                    url: existing.url.clone(),
+                    title: existing.title.clone(),
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Plan updates of an existing PR's title and body according to the sync policy.
+///
+/// With `SyncPolicy::JjWins` every difference is shown right away. With `SyncPolicy::Ask`
+/// the updates are planned with a confirmation, so the user is only asked when the plan
+/// runs and never during a dry run.
+fn sync_title_and_body(
+    pr: &forge::PrInfo,
+    title: &str,
+    body: &str,
+    policy: SyncPolicy,
+    forge_name: &str,
+    operations: &mut Vec<Operation>,
+) {
+    if policy == SyncPolicy::GithubWins {
+        return;
+    }
+
+    let confirmation = |question, current: &str, proposed: &str| {
+        (policy == SyncPolicy::Ask).then(|| Confirmation {
+            question,
+            current: current.to_string(),
+            proposed: proposed.to_string(),
+        })
+    };
+
+    if pr.title != title {
+        if policy == SyncPolicy::JjWins {
+            print_diff(
+                &format!("title of {}", pr.url),
+                forge_name,
+                &pr.title,
+                title,
+            );
+        }
+        operations.push(Operation::UpdateTitle {
+            pr_url: pr.url.clone(),
+            title: title.to_string(),
+            confirm: confirmation(Question::UpdateTitle, &pr.title, title),
+        });
+    }
+
+    let current = pr.body.replace('\r', "");
+    match generated_body(&current) {
+        Some(generated) if generated == body => {}
+        Some(generated) => {
+            if policy == SyncPolicy::JjWins {
+                print_diff(&format!("body of {}", pr.url), forge_name, generated, body);
+            }
+            operations.push(Operation::UpdateBody {
+                pr_url: pr.url.clone(),
+                body: render_body(body),
+                confirm: confirmation(Question::UpdateBody, generated, body),
+            });
+        }
+        None if current.trim() == body => {}
+        None if policy == SyncPolicy::Ask => operations.push(Operation::UpdateBody {
+            pr_url: pr.url.clone(),
+            body: render_body(body),
+            confirm: confirmation(Question::ReplaceEditedBody, &current, body),
+        }),
+        None => println!(
+            "The body of {} was edited on {forge_name}, leaving it alone",
+            pr.url
+        ),
+    }
+}
+
+/// Ask a yes/no question on the terminal, anything but yes counts as no
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "Cannot ask \"{question}\" without a terminal, use --sync jj-wins or --sync github-wins"
+        );
+    }
+
+    print!("{question} [y/N] ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Print a line based diff between what the forge has and what jj has
+fn print_diff(label: &str, forge_name: &str, current: &str, jj: &str) {
+    let old: Vec<&str> = current.lines().collect();
+    let new: Vec<&str> = jj.lines().collect();
+
+    // Longest common subsequence table, lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    println!("{}", format!("--- {label} ({forge_name})").red());
+    println!("{}", format!("+++ {label} (jj)").green());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            println!(" {}", old[i]);
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            println!("{}", format!("-{}", old[i]).red());
+            i += 1;
+        } else {
+            println!("{}", format!("+{}", new[j]).green());
+            j += 1;
+        }
+    }
+}
+
+/// Marks PR bodies written by stack-prs, followed by a hash of the text above it
+const BODY_MARKER: &str = "<!-- stack-prs body ";
+
+/// Build the PR body for a change description body.
+///
+/// The hash in the trailing marker lets later runs notice edits made on GitHub.
+fn render_body(body: &str) -> String {
+    let marker = format!("{BODY_MARKER}{:016x} -->", body_hash(body));
+    if body.is_empty() {
+        marker
+    } else {
+        format!("{body}\n\n{marker}")
+    }
+}
+
+/// Get the description body a PR body was generated from, or `None` if it was edited
+/// on GitHub since. Empty bodies count as generated, there is nothing to lose.
+fn generated_body(pr_body: &str) -> Option<&str> {
+    if pr_body.trim().is_empty() {
+        return Some("");
+    }
+
+    let (body, marker) = pr_body.trim_end().rsplit_once(BODY_MARKER)?;
+    let hash = u64::from_str_radix(marker.strip_suffix(" -->")?, 16).ok()?;
+    let body = body.trim_end();
+    (body_hash(body) == hash).then_some(body)
+}
+
+/// FNV-1a, stable across Rust versions unlike `DefaultHasher`. GitHub may turn line
+/// endings into CRLF, so carriage returns are ignored.
+fn body_hash(body: &str) -> u64 {
+    body.bytes()
+        .filter(|b| *b != b'\r')
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn stack_comment(index: usize, prs: &[&PrLink]) -> String {
+    let mut comment = format!(
+        "{}\n\nThis PR is **{} of {}** in the stack.\n",
+        forge::STACK_COMMENT_HEADING,
+        index + 1,
+        prs.len()
+    );
+
+    if index > 0 {
+        let prev_pr = prs[index - 1];
+        comment.push_str(&format!(
+            "\n⬇️ Previous PR: [{}]({})\n",
+            prev_pr.title, prev_pr.url
+        ));
+    }
+
+    if index < prs.len() - 1 {
+        let next_pr = prs[index + 1];
+        comment.push_str(&format!(
+            "\n⬆️ Next PR: [{}]({})\n",
+            next_pr.title, next_pr.url
+        ));
+    }
+
+    comment
+}
+
+/// Stack comment for fork mode, where every PR targets trunk and contains the commits of
+/// all PRs below it
+fn fork_stack_comment(index: usize, prs: &[&PrLink]) -> String {
+    let mut comment = format!(
+        "{}\n\nThis PR is **{} of {}** in the stack:\n\n",
+        forge::STACK_COMMENT_HEADING,
+        index + 1,
+        prs.len()
+    );
+
+    for (position, pr) in prs.iter().enumerate() {
+        let marker = if position == index {
+            " 👈 this PR"
+        } else {
+            ""
+        };
+        comment.push_str(&format!(
+            "{}. [{}]({}){marker}\n",
+            position + 1,
+            pr.title,
+            pr.url
+        ));
+    }
+
+    comment.push_str(
+        "\nThe branches of this stack live in a fork, so every PR targets the base branch directly.\n",
+    );
+    if index > 0 {
+        comment.push_str(&format!(
+            "This PR also contains the commits of {}. \
+             **Review only the last commit**, and merge the PRs in order.\n",
+            prs_before(index)
+        ));
+    }
+
+    comment
+}
+
+/// Stack comment for a stack that branches: every PR in a nested list under the PR it
+/// builds on. `parents` holds the position of each PR's parent in `prs`.
+fn tree_stack_comment(
+    index: usize,
+    prs: &[&PrLink],
+    parents: &[Option<usize>],
+    fork_mode: bool,
+) -> String {
+    let mut comment = format!(
+        "{}\n\nThis PR is one of {} in a stack that branches:\n\n",
+        forge::STACK_COMMENT_HEADING,
+        prs.len()
+    );
+    push_tree(&mut comment, index, prs, parents, None, 0);
+
+    if !fork_mode {
+        comment.push_str("\nEvery PR targets the PR it is listed under.\n");
+    } else {
+        comment.push_str(
+            "\nThe branches of this stack live in a fork, so every PR targets the base branch directly.\n",
+        );
+        if parents[index].is_some() {
+            comment.push_str(
+                "This PR also contains the commits of the PRs it is listed under. \
+                 **Review only the last commit**, and merge those PRs first.\n",
+            );
+        }
+    }
+
+    comment
+}
+
+/// Add the PRs whose parent is `parent` to the list, each followed by its own children
+fn push_tree(
+    comment: &mut String,
+    index: usize,
+    prs: &[&PrLink],
+    parents: &[Option<usize>],
+    parent: Option<usize>,
+    depth: usize,
+) {
+    for position in (0..prs.len()).filter(|position| parents[*position] == parent) {
+        let marker = if position == index {
+            " 👈 this PR"
+        } else {
+            ""
+        };
+        comment.push_str(&format!(
+            "{}- [{}]({}){marker}\n",
+            "  ".repeat(depth),
+            prs[position].title,
+            prs[position].url
+        ));
+        push_tree(comment, index, prs, parents, Some(position), depth + 1);
+    }
+}
+
+/// PR body for fork mode, marking which commit belongs to a PR with `index` PRs below it
+fn fork_body(index: usize, previous_title: &str, title: &str, body: &str) -> String {
+    let note = format!(
+        "> This PR depends on \"{previous_title}\" and contains the commits of {} \
+         in the stack. **Review only the last commit**, \"{title}\".\n\
+         > The stack comment links all PRs of the stack.",
+        prs_before(index)
+    );
+    if body.is_empty() {
+        note
+    } else {
+        format!("{note}\n\n{body}")
+    }
+}
+
+fn prs_before(count: usize) -> String {
+    if count == 1 {
+        "the PR before it".to_string()
+    } else {
+        format!("the {count} PRs before it")
+    }
+}
+
+/// Rebase the changes into the new stack order, undoing everything if it causes conflicts
+fn apply_rebases(rebases: &[stack::Rebase]) -> Result<()> {
+    println!("Reordering changes to match the stack file:");
+    for rebase in rebases {
+        println!(
+            "  jj rebase --revisions {} --destination {}",
+            rebase.change_id, rebase.destination
+        );
+    }
+
+    let operation_id = jj::current_operation_id()?;
+
+    for rebase in rebases {
+        if let Err(e) = jj::rebase_revision(&rebase.change_id, &rebase.destination) {
+            jj::restore_operation(&operation_id)?;
+            return Err(e.context("Reordering the stack failed, the repository was restored"));
+        }
+    }
+
+    // Changes that were not moved can still end up conflicted on top of the moved ones
+    let change_ids: Vec<&str> = rebases.iter().map(|r| r.change_id.as_str()).collect();
+    let descendants = format!("({})::", change_ids.join(" | "));
+    let conflicted = jj::conflicted_changes(&[&descendants])?;
+    if !conflicted.is_empty() {
+        jj::restore_operation(&operation_id)?;
+        anyhow::bail!(
+            "Reordering the stack causes conflicts in {}, the repository was restored",
+            conflicted.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// The commit a bookmark points at on the remote, `None` if the remote has no such branch
+fn remote_commit(bookmark: &str, remote: &str) -> Result<Option<String>> {
+    Ok(jj::bookmark_targets(remote)?
+        .remove(bookmark)
+        .and_then(|target| target.remote))
+}
+
+/// The existing PR of the stack with the given URL
+fn existing_pr<'a>(stack: &'a [StackPr], pr_url: &str) -> Option<&'a forge::PrInfo> {
+    stack
+        .iter()
+        .filter_map(|pr| pr.existing.as_ref())
+        .find(|existing| existing.url == pr_url)
+}
+
+/// Abandon the changes of merged PRs and rebase the rest of the stack onto trunk,
+/// undoing everything if it causes conflicts
+fn drop_merged(abandon: &[String], rebase: Option<&str>) -> Result<()> {
+    let operation_id = jj::current_operation_id()?;
+
+    if !abandon.is_empty() {
+        println!("Abandoning merged changes {}", abandon.join(", "));
+        jj::abandon(abandon)?;
+    }
+
+    if let Some(change_id) = rebase {
+        println!("Rebasing the rest of the stack onto {TRUNK_REVSET}");
+        if let Err(e) = jj::rebase_source(change_id, TRUNK_REVSET) {
+            jj::restore_operation(&operation_id)?;
+            return Err(e.context("Rebasing the stack failed, the repository was restored"));
+        }
+
+        let descendants = format!("{change_id}::");
+        let conflicted = jj::conflicted_changes(&[&descendants])?;
+        if !conflicted.is_empty() {
+            jj::restore_operation(&operation_id)?;
+            anyhow::bail!(
+                "Rebasing the stack onto {TRUNK_REVSET} causes conflicts in {}, the repository was restored.\n\
+                 Rebase it yourself, resolve the conflicts and run stack-prs sync again",
+                conflicted.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Print a before/after table of the PRs whose base branch was changed
+fn print_base_changes(changes: &[BaseChange]) {
+    let pr_width = changes
+        .iter()
+        .map(|c| c.pr_url.len())
+        .max()
+        .unwrap_or(0)
+        .max("PR".len());
+    let before_width = changes
+        .iter()
+        .map(|c| c.before.len())
+        .max()
+        .unwrap_or(0)
+        .max("Before".len());
+
+    println!("\nRetargeted PRs:");
+    println!("  {:pr_width$}  {:before_width$}  After", "PR", "Before");
+    for change in changes {
+        println!(
+            "  {:pr_width$}  {:before_width$}  {}",
+            change.pr_url,
+            change.before.red(),
+            change.after.green()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendered_body_is_recognised_as_generated() {
+        let body = "First paragraph.\n\nSecond paragraph.";
+        assert_eq!(generated_body(&render_body(body)), Some(body));
+    }
+
+    #[test]
+    fn rendered_empty_body_is_just_the_marker() {
+        let rendered = render_body("");
+        assert!(rendered.starts_with(BODY_MARKER));
+        assert_eq!(generated_body(&rendered), Some(""));
+    }
+
+    #[test]
+    fn empty_pr_body_counts_as_generated() {
+        assert_eq!(generated_body(""), Some(""));
+        assert_eq!(generated_body(" \n"), Some(""));
+    }
+
+    #[test]
+    fn edited_body_is_not_generated() {
+        let rendered = render_body("Original text.");
+        let edited = rendered.replace("Original", "Edited");
+        assert_eq!(generated_body(&edited), None);
+    }
+
+    #[test]
+    fn body_without_marker_is_not_generated() {
+        assert_eq!(generated_body("Written by hand."), None);
+    }
+
+    #[test]
+    fn marker_with_a_broken_hash_is_not_generated() {
+        assert_eq!(
+            generated_body(&format!("Text\n\n{BODY_MARKER}xyz -->")),
+            None
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings_do_not_count_as_edits() {
+        let body = "Line 1\nLine 2";
+        let rendered = render_body(body).replace('\n', "\r\n");
+        assert_eq!(body_hash(body), body_hash(&body.replace('\n', "\r\n")));
+        assert!(generated_body(&rendered).is_some());
+    }
+
+    #[test]
+    fn trailing_whitespace_after_the_marker_is_ignored() {
+        let body = "Some text";
+        assert_eq!(
+            generated_body(&format!("{}\n\n", render_body(body))),
+            Some(body)
+        );
+    }
+
+    #[test]
+    fn body_hash_is_stable() {
+        // FNV-1a of the empty string and of "a"
+        assert_eq!(body_hash(""), 0xcbf29ce484222325);
+        assert_eq!(body_hash("a"), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -1,17 +1,34 @@
 use anyhow::{Context, Result};
 use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
 use std::process::Command;
 use tempfile::NamedTempFile;
 
-use crate::jj::Change;
+use crate::forge::PrCache;
+use crate::jj::{self, Change};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Action {
     Skip,
     CreatePr,
+    /// Create or keep the PR as a draft
+    Draft,
+}
+
+impl Action {
+    /// The keyword written to the stack file for this action
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Skip => "skip",
+            Action::CreatePr => "pr",
+            Action::Draft => "draft",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -19,112 +36,1098 @@ pub struct StackEntry {
     pub action: Action,
     pub change_id: String,
     pub description: String,
+    /// PR body, taken from the rest of the change description
+    pub body: String,
     pub bookmark: Option<String>,
+    /// The entry whose PR this one builds on, `None` at the bottom of the stack
+    pub parent: Option<String>,
+}
+
+/// A single `jj rebase` needed to make the commit graph match the stack file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rebase {
+    pub change_id: String,
+    pub destination: String,
 }
 
 const HEADER: &str = r#"# The following file represents your stack in the order it will applied, top to bottom.
+# The first PR will be opened against "{base_branch}", every following PR against the one above it.
 # The first column can be one of:
 # * "skip" or "s": to skip this change entirely (can also just delete the line)
 # * "pr": to create or update a PR for this change
 #         If a bookmark exists and has a PR, it will be kept in the stack
 #         If a bookmark exists without a PR, a PR will be created
 #         If no bookmark exists, jj will create an automatic one
+#         A draft PR is marked as ready for review
+# * "draft" or "d": like "pr", but the PR is created as or converted to a draft
+# Changes that already have a PR start out with "pr" or "draft" to match it, so a PR only
+# changes its draft state when you change its line.
+# Reordering lines rebases the changes into the new order.
+# A stack that branches is drawn as a tree, and every PR targets the PR of the change it
+# is drawn on. The lines of such a stack cannot be reordered.
 # the other columns are:
 # * the change ID
-# * the change description
+# * the first line of the change description, used as the PR title (the rest becomes the PR body)
 # * if present, the bookmark name (can be added/edited if not set)
+# Columns are comma separated. Values containing commas or quotes are wrapped in
+# double quotes, with any quote inside doubled: "Fix foo, bar and ""baz"""
+# Changes that are conflicted, divergent, empty, have no description, were written by
+# someone else or only have a bookmark on a remote are noted above their line.
+# Conflicted changes cannot get a PR unless --force is given, divergent changes cannot get
+# one at all, and a change without a title is asked for one.
 "#;
 
-/// Create a temporary file with the stack, open it in $EDITOR, and parse the result
-pub fn edit_stack(changes: Vec<Change>) -> Result<Vec<StackEntry>> {
-    // Create the initial stack file content
-    let mut content = String::from(HEADER);
+/// A problem with a single line of the stack file
+#[derive(Debug, Clone)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
 
-    for change in changes.iter().rev() {
-        let action = "pr";
-        let bookmark_str = change.bookmark.as_deref().unwrap_or("");
-        content.push_str(&format!(
-            "{},{},{},{}\n",
-            action, change.change_id, change.description, bookmark_str
-        ));
+impl std::fmt::Display for LineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
     }
+}
 
-    // Create a temporary file
-    let mut temp_file = NamedTempFile::new().context("Failed to create temporary file")?;
+/// Prefix of the comments stack-prs adds above lines it could not accept
+const ERROR_PREFIX: &str = "# ERROR: ";
 
-    temp_file
-        .write_all(content.as_bytes())
-        .context("Failed to write to temporary file")?;
+/// Create a temporary file with the stack, open it in $EDITOR, and parse the result.
+///
+/// If the edited file has problems, the editor is reopened with the errors added as
+/// comments above the offending lines until the file is valid.
+pub fn edit_stack(
+    changes: &[Change],
+    base_branch: &str,
+    default_action: Action,
+    prs: &PrCache,
+    force: bool,
+) -> Result<Vec<StackEntry>> {
+    // Create the initial stack file content
+    let mut content = HEADER.replace("{base_branch}", base_branch);
+    let user_email = jj::config_value("user.email")?;
+
+    for (change, tree) in layout(changes) {
+        let action = offered_action(change, default_action, prs).as_str();
+        let bookmark_str = change.bookmarks.first().map(String::as_str).unwrap_or("");
+        // Comments continue the line of the branch they are on
+        let comment_tree = match tree.strip_suffix(BRANCH).or(tree.strip_suffix(LAST_BRANCH)) {
+            Some(outer) => format!("{outer}{CONTINUE}"),
+            None => tree.clone(),
+        };
+        if change.bookmarks.len() > 1 {
+            content.push_str(&format!(
+                "{comment_tree}# {} also has the bookmarks: {}\n",
+                change.change_id,
+                change.bookmarks[1..].join(", ")
+            ));
+        }
+        let notes = state_notes(change, user_email.as_deref());
+        if !notes.is_empty() {
+            content.push_str(&format!(
+                "{comment_tree}# {}: {}\n",
+                change.change_id,
+                notes.join(", ")
+            ));
+        }
+        content.push_str(&tree);
+        content.push_str(&write_record(&[
+            action,
+            &change.change_id,
+            change.title(),
+            bookmark_str,
+        ]));
+        content.push('\n');
+    }
 
+    let existing_bookmarks = jj::local_bookmarks()?;
+
+    // Create a temporary file
+    let temp_file = NamedTempFile::new().context("Failed to create temporary file")?;
     let temp_path = temp_file.path().to_owned();
 
     // Get the editor from environment
     let editor = env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
 
-    debug!(
-        "Executing command: {} {}",
-        editor,
-        temp_path.to_string_lossy()
-    );
+    loop {
+        fs::write(&temp_path, content.as_bytes()).context("Failed to write to temporary file")?;
+
+        debug!(
+            "Executing command: {} {}",
+            editor,
+            temp_path.to_string_lossy()
+        );
+
+        // Open the editor
+        let status = Command::new(&editor)
+            .arg(&temp_path)
+            .status()
+            .context(format!("Failed to open editor: {editor}"))?;
+
+        if !status.success() {
+            anyhow::bail!("Editor exited with non-zero status");
+        }
+
+        // Read back the edited file, dropping errors reported by a previous round
+        let edited_content =
+            fs::read_to_string(&temp_path).context("Failed to read edited file")?;
+        let edited_content = strip_errors(&edited_content);
+
+        // Parse and validate the edited content
+        let (entries, mut errors) = parse_stack_file(&edited_content);
+        errors.extend(validate(&entries, changes, &existing_bookmarks, force));
+
+        if errors.is_empty() {
+            return Ok(complete(entries, changes));
+        }
+
+        for error in &errors {
+            eprintln!("Error in stack file, {error}");
+        }
+        content = annotate_errors(&edited_content, errors);
+    }
+}
+
+/// The stack as it is offered in the editor, without asking: every change bottom to top
+/// with the action of its existing PR or `default_action`, and its first bookmark
+pub fn default_stack(
+    changes: &[Change],
+    default_action: Action,
+    prs: &PrCache,
+    force: bool,
+) -> Result<Vec<StackEntry>> {
+    if default_action != Action::Skip {
+        let blocked: Vec<String> = changes
+            .iter()
+            .rev()
+            .filter_map(|change| {
+                blocking_state(change, force)
+                    .map(|reason| format!("  {} {reason}", change.change_id))
+            })
+            .collect();
+        if !blocked.is_empty() {
+            anyhow::bail!("Some changes cannot get a PR:\n{}", blocked.join("\n"));
+        }
+    }
+
+    let entries = layout(changes)
+        .into_iter()
+        .map(|(change, _)| {
+            (
+                0,
+                StackEntry {
+                    action: offered_action(change, default_action, prs),
+                    change_id: change.change_id.clone(),
+                    description: change.title().to_string(),
+                    body: String::new(),
+                    bookmark: change.bookmarks.first().cloned(),
+                    parent: None,
+                },
+            )
+        })
+        .collect();
+    Ok(complete(entries, changes))
+}
+
+/// The action a change is offered with. A change whose first bookmark has a PR keeps that
+/// PR's draft state, `default_action` only applies to new PRs.
+fn offered_action(change: &Change, default_action: Action, prs: &PrCache) -> Action {
+    match change
+        .bookmarks
+        .first()
+        .and_then(|bookmark| prs.get(bookmark))
+    {
+        Some(pr) if pr.is_draft => Action::Draft,
+        Some(_) => Action::CreatePr,
+        None => default_action,
+    }
+}
+
+/// Read a stack file prepared ahead of time, in the same format as the one opened in the editor
+pub fn read_stack_file(path: &Path, changes: &[Change], force: bool) -> Result<Vec<StackEntry>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the stack file {}", path.display()))?;
+    let content = strip_errors(&content);
+
+    let (entries, mut errors) = parse_stack_file(&content);
+    errors.extend(validate(&entries, changes, &jj::local_bookmarks()?, force));
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| e.line);
+        let errors: Vec<String> = errors.iter().map(|e| format!("  {e}")).collect();
+        anyhow::bail!(
+            "The stack file {} has problems:\n{}",
+            path.display(),
+            errors.join("\n")
+        );
+    }
+
+    Ok(complete(entries, changes))
+}
+
+/// Ask on the terminal for a PR title for every entry that gets a PR but has none, which
+/// happens when the change has no description and no title was written in the stack file
+pub fn ask_missing_titles(entries: &mut [StackEntry]) -> Result<()> {
+    let missing = entries
+        .iter_mut()
+        .filter(|entry| entry.action != Action::Skip && entry.description.trim().is_empty());
+    for entry in missing {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!(
+                "Change {} has no description to use as PR title. Describe it with `jj describe` \
+                 or write a title in the third column of the stack file",
+                entry.change_id
+            );
+        }
+
+        loop {
+            print!("Change {} has no description. PR title: ", entry.change_id);
+            std::io::stdout().flush()?;
+
+            let mut title = String::new();
+            if std::io::stdin().read_line(&mut title)? == 0 {
+                anyhow::bail!("No PR title given for change {}", entry.change_id);
+            }
+            let title = title.trim();
+            if !title.is_empty() {
+                entry.description = title.to_string();
+                break;
+            }
+        }
+    }
+    Ok(())
+}
 
-    // Open the editor
-    let status = Command::new(&editor)
-        .arg(&temp_path)
-        .status()
-        .context(format!("Failed to open editor: {editor}"))?;
+/// Why a change cannot get a PR, if it can't. `force` allows conflicted changes, but not
+/// divergent ones: their change ID names several commits, so jj cannot put a bookmark on it.
+fn blocking_state(change: &Change, force: bool) -> Option<&'static str> {
+    if change.divergent {
+        Some("is divergent, resolve it with jj first or skip it")
+    } else if change.conflict && !force {
+        Some("has unresolved conflicts, resolve it first, skip it or pass --force")
+    } else {
+        None
+    }
+}
 
-    if !status.success() {
-        anyhow::bail!("Editor exited with non-zero status");
+/// What is worth knowing about a change before opening a PR for it
+fn state_notes(change: &Change, user_email: Option<&str>) -> Vec<String> {
+    let mut notes = Vec::new();
+    if change.conflict {
+        notes.push("conflicted".to_string());
     }
+    if change.divergent {
+        notes.push("divergent".to_string());
+    }
+    if change.empty {
+        notes.push("empty".to_string());
+    }
+    if change.title().is_empty() {
+        notes.push("no description".to_string());
+    }
+    if user_email.is_some_and(|email| email != change.author.email) {
+        notes.push(format!(
+            "by {} <{}>",
+            change.author.name, change.author.email
+        ));
+    }
+    // The git remote of colocated repositories mirrors the local bookmarks
+    for bookmark in &change.remote_bookmarks {
+        if bookmark.remote != "git" && !change.bookmarks.contains(&bookmark.name) {
+            notes.push(format!(
+                "{}@{} has no local bookmark, track it with jj to use it",
+                bookmark.name, bookmark.remote
+            ));
+        }
+    }
+    notes
+}
 
-    // Read back the edited file
-    let edited_content = fs::read_to_string(&temp_path).context("Failed to read edited file")?;
+/// Fill in what the stack file does not hold: the PR bodies, from the descriptions of the
+/// changes, and the parent of every entry.
+///
+/// In a linear stack every PR builds on the one above it in the file, which is where the
+/// rebases put it. In a branching stack it builds on the closest ancestor in jj that
+/// gets a PR.
+fn complete(entries: Vec<(usize, StackEntry)>, changes: &[Change]) -> Vec<StackEntry> {
+    let linear = is_linear(changes);
+    let graph_parents = stack_parents(changes);
+    let with_pr: HashSet<String> = entries
+        .iter()
+        .filter(|(_, entry)| entry.action != Action::Skip)
+        .map(|(_, entry)| entry.change_id.clone())
+        .collect();
 
-    // Parse the edited content
-    parse_stack_file(&edited_content)
+    let mut previous: Option<String> = None;
+    entries
+        .into_iter()
+        .map(|(_, mut entry)| {
+            if let Some(change) = changes.iter().find(|c| c.change_id == entry.change_id) {
+                entry.body = change.body().to_string();
+            }
+
+            entry.parent = if linear {
+                previous.clone()
+            } else {
+                let mut ancestor = graph_parents
+                    .get(entry.change_id.as_str())
+                    .copied()
+                    .flatten();
+                while let Some(id) = ancestor.filter(|id| !with_pr.contains(*id)) {
+                    ancestor = graph_parents.get(id).copied().flatten();
+                }
+                ancestor.map(str::to_string)
+            };
+            if entry.action != Action::Skip {
+                previous = Some(entry.change_id.clone());
+            }
+            entry
+        })
+        .collect()
 }
 
-fn parse_stack_file(content: &str) -> Result<Vec<StackEntry>> {
+/// The change each change builds on within the stack: its first parent among `changes`,
+/// `None` if it sits on something outside of them
+pub fn stack_parents(changes: &[Change]) -> HashMap<&str, Option<&str>> {
+    let ids: HashSet<&str> = changes.iter().map(|c| c.change_id.as_str()).collect();
+    changes
+        .iter()
+        .map(|change| {
+            let parent = change
+                .parents
+                .iter()
+                .map(String::as_str)
+                .find(|parent| ids.contains(parent));
+            (change.change_id.as_str(), parent)
+        })
+        .collect()
+}
+
+/// Whether the changes form a single line, each one on top of the one before it
+fn is_linear(changes: &[Change]) -> bool {
+    let parents = stack_parents(changes);
+    // jj log lists the newest change first
+    changes
+        .windows(2)
+        .all(|pair| parents[pair[0].change_id.as_str()] == Some(pair[1].change_id.as_str()))
+}
+
+/// Tree drawn in front of the first line of a branch, and of the last branch
+const BRANCH: &str = "├─ ";
+const LAST_BRANCH: &str = "└─ ";
+/// Tree drawn in front of the lines that continue a branch
+const CONTINUE: &str = "│  ";
+const LAST_CONTINUE: &str = "   ";
+
+/// The changes in the order of the stack file, parents before their children, each with
+/// the tree to draw in front of its line. A linear stack is drawn without a tree.
+pub fn layout(changes: &[Change]) -> Vec<(&Change, String)> {
+    let parents = stack_parents(changes);
+    let mut children: HashMap<Option<&str>, Vec<&Change>> = HashMap::new();
+    // Oldest first, so branches appear in the order they were started
+    for change in changes.iter().rev() {
+        children
+            .entry(parents[change.change_id.as_str()])
+            .or_default()
+            .push(change);
+    }
+
+    let mut lines = Vec::new();
+    layout_children(&children, None, "", &mut lines);
+    lines
+}
+
+/// The tree to draw in front of the lines that follow a change's line, given the tree of
+/// that line
+pub fn continuation(tree: &str) -> String {
+    if let Some(outer) = tree.strip_suffix(BRANCH) {
+        format!("{outer}{CONTINUE}")
+    } else if let Some(outer) = tree.strip_suffix(LAST_BRANCH) {
+        format!("{outer}{LAST_CONTINUE}")
+    } else {
+        tree.to_string()
+    }
+}
+
+fn layout_children<'a>(
+    children: &HashMap<Option<&'a str>, Vec<&'a Change>>,
+    parent: Option<&'a str>,
+    tree: &str,
+    lines: &mut Vec<(&'a Change, String)>,
+) {
+    let Some(siblings) = children.get(&parent) else {
+        return;
+    };
+
+    // A single child continues the line of its parent
+    if let [only] = siblings.as_slice() {
+        lines.push((only, tree.to_string()));
+        layout_children(children, Some(only.change_id.as_str()), tree, lines);
+        return;
+    }
+
+    for (index, child) in siblings.iter().enumerate() {
+        let (first, rest) = if index + 1 == siblings.len() {
+            (LAST_BRANCH, LAST_CONTINUE)
+        } else {
+            (BRANCH, CONTINUE)
+        };
+        lines.push((child, format!("{tree}{first}")));
+        layout_children(
+            children,
+            Some(child.change_id.as_str()),
+            &format!("{tree}{rest}"),
+            lines,
+        );
+    }
+}
+
+fn strip_errors(content: &str) -> String {
+    content
+        .lines()
+        .filter(|line| !line.starts_with(ERROR_PREFIX))
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// Insert each error as a comment above the line it refers to
+fn annotate_errors(content: &str, mut errors: Vec<LineError>) -> String {
+    errors.sort_by_key(|e| e.line);
+
+    let mut annotated = format!(
+        "{ERROR_PREFIX}The stack file has {} problem(s), fix them and save again.\n\
+         {ERROR_PREFIX}Delete every line to abort without changing anything.\n",
+        errors.len()
+    );
+    let mut errors = errors.into_iter().peekable();
+
+    for (index, line) in content.lines().enumerate() {
+        while let Some(error) = errors.next_if(|e| e.line == index + 1) {
+            annotated.push_str(&format!("{ERROR_PREFIX}{}\n", error.message));
+        }
+        annotated.push_str(line);
+        annotated.push('\n');
+    }
+
+    annotated
+}
+
+fn parse_stack_file(content: &str) -> (Vec<(usize, StackEntry)>, Vec<LineError>) {
     let mut entries = Vec::new();
+    let mut errors = Vec::new();
 
-    for line in content.lines() {
-        let line = line.trim();
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        // Drop the tree a branching stack is drawn with
+        let line = line
+            .trim_start_matches(['│', '├', '└', '─', ' ', '\t'])
+            .trim_end();
 
         // Skip empty lines and comments
         if line.is_empty() || line.starts_with('#') {
             continue;
         }
 
-        let parts: Vec<&str> = line.split(',').collect();
-        if parts.len() < 3 {
-            continue; // Skip malformed lines
-        }
+        let mut error = |message: String| {
+            errors.push(LineError {
+                line: line_number,
+                message,
+            })
+        };
 
-        let action_str = parts[0].trim();
-        let change_id = parts[1].trim().to_string();
-        let description = parts[2].trim().to_string();
-        let bookmark = if parts.len() > 3 && !parts[3].trim().is_empty() {
-            Some(parts[3].trim().to_string())
-        } else {
-            None
+        let parts = match parse_record(line) {
+            Ok(parts) => parts,
+            Err(message) => {
+                error(message);
+                continue;
+            }
         };
+        if parts.len() < 3 || parts.len() > 4 {
+            error(format!("expected 3 or 4 columns, found {}", parts.len()));
+            continue;
+        }
+
+        let action_str = parts[0].as_str();
+        let change_id = parts[1].clone();
+        let description = parts[2].clone();
+        let bookmark = parts.get(3).filter(|b| !b.is_empty()).cloned();
 
         let action = match action_str {
             "skip" | "s" => Action::Skip,
             "pr" => Action::CreatePr,
+            "draft" | "d" => Action::Draft,
             _ => {
-                eprintln!("Warning: Unknown action '{action_str}', skipping line");
+                error(format!(
+                    "unknown action '{action_str}', expected one of: pr, draft, d, skip, s"
+                ));
                 continue;
             }
         };
 
-        entries.push(StackEntry {
-            action,
-            change_id,
-            description,
-            bookmark,
+        entries.push((
+            line_number,
+            StackEntry {
+                action,
+                change_id,
+                description,
+                body: String::new(),
+                bookmark,
+                parent: None,
+            },
+        ));
+    }
+
+    (entries, errors)
+}
+
+/// Check the parsed entries against each other and against the changes that were offered.
+/// Conflicted changes may only be skipped, unless `force` is set, and divergent ones
+/// may only be skipped.
+///
+/// `existing_bookmarks` are the local bookmarks and the changes they point at. A bookmark
+/// may only be moved forward onto its entry from an ancestor in the stack, so a typo
+/// cannot take over an unrelated branch.
+fn validate(
+    entries: &[(usize, StackEntry)],
+    changes: &[Change],
+    existing_bookmarks: &HashMap<String, Option<String>>,
+    force: bool,
+) -> Vec<LineError> {
+    let graph_parents = stack_parents(changes);
+    let mut errors = Vec::new();
+    let mut seen_changes = HashSet::new();
+    let mut seen_bookmarks = HashSet::new();
+
+    for (line, entry) in entries {
+        let mut error = |message: String| {
+            errors.push(LineError {
+                line: *line,
+                message,
+            })
+        };
+
+        match changes.iter().find(|c| c.change_id == entry.change_id) {
+            None => error(format!(
+                "change '{}' is not one of the changes in the stack",
+                entry.change_id
+            )),
+            Some(change) if entry.action != Action::Skip => {
+                if let Some(reason) = blocking_state(change, force) {
+                    error(format!("change '{}' {reason}", entry.change_id));
+                }
+            }
+            Some(_) => {}
+        }
+
+        if !seen_changes.insert(&entry.change_id) {
+            error(format!(
+                "change '{}' is already listed above",
+                entry.change_id
+            ));
+        }
+
+        if let Some(bookmark) = entry.bookmark.as_deref() {
+            if let Some(problem) = bookmark_name_problem(bookmark) {
+                error(format!("invalid bookmark name '{bookmark}': {problem}"));
+            }
+
+            if !seen_bookmarks.insert(bookmark) {
+                error(format!("bookmark '{bookmark}' is already used above"));
+            }
+
+            match existing_bookmarks.get(bookmark) {
+                None => {}
+                Some(None) => error(format!(
+                    "bookmark '{bookmark}' is conflicted, resolve it with jj first"
+                )),
+                Some(Some(target)) => {
+                    let mut ancestor = Some(entry.change_id.as_str());
+                    while let Some(id) = ancestor.filter(|id| id != target) {
+                        ancestor = graph_parents.get(id).copied().flatten();
+                    }
+                    if ancestor.is_none() {
+                        error(format!(
+                            "bookmark '{bookmark}' already exists on change {target}, \
+                             which is not this change or below it in the stack"
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    // Rebasing only knows how to make the changes linear, which would undo the branches
+    if !is_linear(changes) {
+        let positions: HashMap<&str, usize> = layout(changes)
+            .iter()
+            .enumerate()
+            .map(|(position, (change, _))| (change.change_id.as_str(), position))
+            .collect();
+        let mut highest = None;
+        for (line, entry) in entries {
+            let Some(&position) = positions.get(entry.change_id.as_str()) else {
+                continue;
+            };
+            if highest.is_some_and(|highest| position < highest) {
+                errors.push(LineError {
+                    line: *line,
+                    message: "the stack branches, so its lines cannot be reordered. \
+                              Rebase the changes with jj instead"
+                        .to_string(),
+                });
+            } else {
+                highest = Some(position);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Describe why a bookmark name cannot be pushed as a git branch, if it can't
+fn bookmark_name_problem(name: &str) -> Option<&'static str> {
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_control() || c.is_whitespace() || "~^:?*[\\".contains(*c))
+    {
+        return Some(match c {
+            c if c.is_whitespace() => "must not contain whitespace",
+            c if c.is_control() => "must not contain control characters",
+            _ => "must not contain any of ~ ^ : ? * [ \\",
         });
     }
+    if name.starts_with('-') {
+        return Some("must not start with '-'");
+    }
+    if name.contains("..") || name.contains("@{") || name == "@" {
+        return Some("must not contain '..' or '@{' or be '@'");
+    }
+    if name
+        .split('/')
+        .any(|part| part.is_empty() || part.starts_with('.') || part.ends_with(".lock"))
+    {
+        return Some("every '/' separated part must be non-empty, not start with '.' and not end with '.lock'");
+    }
+    if name.ends_with('.') {
+        return Some("must not end with '.'");
+    }
+    None
+}
+
+/// Join fields into a single line, quoting the ones that would not survive `parse_record`
+fn write_record(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|field| {
+            let needs_quotes = field.contains([',', '"', '\n', '\r'])
+                || field.trim() != *field
+                || field.starts_with('#');
+            if needs_quotes {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Split a line into fields, honouring double quoted fields with `""` as an escaped quote.
+/// Whitespace around unquoted fields is ignored.
+fn parse_record(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+
+        let mut field = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => {
+                        return Err(format!("unterminated quote in column {}", fields.len() + 1))
+                    }
+                }
+            }
+            while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+            match chars.peek() {
+                None | Some(',') => {}
+                Some(c) => {
+                    return Err(format!(
+                        "unexpected '{c}' after closing quote in column {}",
+                        fields.len() + 1
+                    ))
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                if c == '"' {
+                    return Err(format!(
+                        "unexpected quote in unquoted column {}, wrap the value in quotes and double any quote inside",
+                        fields.len() + 1
+                    ));
+                }
+                field.push(c);
+            }
+            field.truncate(field.trim_end().len());
+        }
+
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+/// Work out the rebases needed to make the changes linear in the order of the stack file.
+///
+/// `changes` is in `jj log` order (newest first). Changes whose lines were deleted
+/// are left where they are. Returns no rebases when the order is unchanged.
+pub fn plan_rebases(changes: &[Change], entries: &[StackEntry]) -> Vec<Rebase> {
+    // The lines of a branching stack keep their order, see `validate`
+    if !is_linear(changes) {
+        return Vec::new();
+    }
+
+    let original_order: Vec<&str> = changes
+        .iter()
+        .rev()
+        .map(|c| c.change_id.as_str())
+        .filter(|id| entries.iter().any(|e| e.change_id == *id))
+        .collect();
+    let new_order: Vec<&str> = entries.iter().map(|e| e.change_id.as_str()).collect();
+
+    let Some(first_moved) = new_order
+        .iter()
+        .zip(original_order.iter())
+        .position(|(new, original)| new != original)
+    else {
+        return Vec::new();
+    };
+
+    // Rebasing one revision at a time keeps every change below the first moved one untouched.
+    // The bottom of the stack goes where the oldest listed change sits now. Changes whose
+    // lines were deleted, like trunk itself, stay below it.
+    let bottom = changes
+        .iter()
+        .find(|c| c.change_id == original_order[0])
+        .and_then(|c| c.parents.first())
+        .map_or("trunk()", String::as_str);
+    (first_moved..new_order.len())
+        .map(|index| Rebase {
+            change_id: new_order[index].to_string(),
+            destination: if index == 0 {
+                bottom.to_string()
+            } else {
+                new_order[index - 1].to_string()
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A change with a description, on top of `parent`
+    fn change(change_id: &str, parent: &str) -> Change {
+        Change {
+            change_id: change_id.to_string(),
+            commit_id: format!("{change_id}-commit"),
+            description: format!("Change {change_id}\n"),
+            bookmarks: Vec::new(),
+            remote_bookmarks: Vec::new(),
+            parents: vec![parent.to_string()],
+            author: jj::Author {
+                name: "A".to_string(),
+                email: "a@example.com".to_string(),
+            },
+            empty: false,
+            conflict: false,
+            divergent: false,
+        }
+    }
+
+    /// aaa <- bbb <- ccc, newest first like `jj log`
+    fn linear_changes() -> Vec<Change> {
+        vec![
+            change("ccc", "bbb"),
+            change("bbb", "aaa"),
+            change("aaa", "trunk"),
+        ]
+    }
+
+    /// aaa <- bbb and aaa <- ccc, newest first like `jj log`
+    fn branching_changes() -> Vec<Change> {
+        vec![
+            change("ccc", "aaa"),
+            change("bbb", "aaa"),
+            change("aaa", "trunk"),
+        ]
+    }
+
+    /// Parse and validate a stack file, returning the line and message of every error
+    fn check(content: &str, changes: &[Change]) -> Vec<(usize, String)> {
+        check_with_bookmarks(content, changes, &HashMap::new())
+    }
+
+    fn check_with_bookmarks(
+        content: &str,
+        changes: &[Change],
+        existing_bookmarks: &HashMap<String, Option<String>>,
+    ) -> Vec<(usize, String)> {
+        let (entries, mut errors) = parse_stack_file(content);
+        errors.extend(validate(&entries, changes, existing_bookmarks, false));
+        errors.into_iter().map(|e| (e.line, e.message)).collect()
+    }
+
+    #[test]
+    fn stack_file_parses_actions_and_bookmarks() {
+        let (entries, errors) =
+            parse_stack_file("# comment\n\npr,aaa,First,feat-a\nd,bbb,Second\ns,ccc,Third,\n");
+        assert!(errors.is_empty());
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(line, e)| (*line, e.action, e.change_id.as_str(), e.bookmark.as_deref()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (3, Action::CreatePr, "aaa", Some("feat-a")),
+                (4, Action::Draft, "bbb", None),
+                (5, Action::Skip, "ccc", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn stack_file_rejects_unknown_actions_and_column_counts() {
+        let errors = check("merge,aaa,First\npr,bbb\n", &linear_changes());
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].0, 1);
+        assert!(errors[0].1.starts_with("unknown action 'merge'"));
+        assert_eq!(
+            errors[1],
+            (2, "expected 3 or 4 columns, found 2".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unknown_change_ids() {
+        let errors = check("pr,aaa,First\npr,zzz,Unknown\n", &linear_changes());
+        assert_eq!(
+            errors,
+            [(
+                2,
+                "change 'zzz' is not one of the changes in the stack".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_duplicate_change_ids() {
+        let errors = check(
+            "pr,aaa,First\npr,bbb,Second\npr,aaa,Again\n",
+            &linear_changes(),
+        );
+        assert_eq!(
+            errors,
+            [(3, "change 'aaa' is already listed above".to_string())]
+        );
+    }
 
-    Ok(entries)
+    #[test]
+    fn validate_rejects_invalid_and_duplicate_bookmark_names() {
+        let content = "pr,aaa,First,feat a\npr,bbb,Second,feat..b\npr,ccc,Third,-feat\n";
+        let errors = check(content, &linear_changes());
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [1, 2, 3]);
+        assert!(errors
+            .iter()
+            .all(|(_, message)| message.starts_with("invalid bookmark name")));
+
+        let duplicates = check("pr,aaa,First,feat\npr,bbb,Second,feat\n", &linear_changes());
+        assert_eq!(
+            duplicates,
+            [(2, "bookmark 'feat' is already used above".to_string())]
+        );
+    }
+
+    #[test]
+    fn validate_only_moves_bookmarks_forward() {
+        let existing = HashMap::from([
+            ("feat-a".to_string(), Some("aaa".to_string())),
+            ("feat-c".to_string(), Some("ccc".to_string())),
+            ("broken".to_string(), None),
+        ]);
+        // feat-a may move up from aaa to bbb, but feat-c may not move down to aaa
+        let content = "pr,aaa,First,feat-c\npr,bbb,Second,feat-a\npr,ccc,Third,broken\n";
+        let errors = check_with_bookmarks(content, &linear_changes(), &existing);
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [1, 3]);
+        assert!(errors[0].1.contains("already exists on change ccc"));
+        assert!(errors[1].1.contains("is conflicted"));
+    }
+
+    #[test]
+    fn force_allows_conflicted_but_not_divergent_changes() {
+        let mut changes = linear_changes();
+        changes[0].conflict = true;
+        changes[1].divergent = true;
+        let (entries, _) = parse_stack_file("pr,aaa,First\npr,bbb,Second\npr,ccc,Third\n");
+
+        let errors = |force| -> Vec<usize> {
+            validate(&entries, &changes, &HashMap::new(), force)
+                .iter()
+                .map(|e| e.line)
+                .collect()
+        };
+        assert_eq!(errors(false), [2, 3]);
+        assert_eq!(errors(true), [2]);
+
+        let (skipped, _) = parse_stack_file("pr,aaa,First\ns,bbb,Second\npr,ccc,Third\n");
+        assert!(validate(&skipped, &changes, &HashMap::new(), true).is_empty());
+    }
+
+    #[test]
+    fn validate_allows_reordering_a_linear_stack() {
+        let errors = check(
+            "pr,ccc,Third\npr,aaa,First\npr,bbb,Second\n",
+            &linear_changes(),
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn validate_rejects_reordering_a_branching_stack() {
+        let changes = branching_changes();
+        let offered = "pr,aaa,First\n├─ pr,bbb,Second\n└─ pr,ccc,Third\n";
+        assert!(check(offered, &changes).is_empty());
+
+        let errors = check(
+            "pr,aaa,First\n└─ pr,ccc,Third\n├─ pr,bbb,Second\n",
+            &changes,
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 3);
+        assert!(errors[0].1.contains("cannot be reordered"));
+    }
+
+    #[test]
+    fn errors_are_annotated_and_stripped_again() {
+        let content = "# header\npr,aaa,First\nmerge,bbb,Second\npr,zzz,Unknown\n";
+        let (entries, mut line_errors) = parse_stack_file(content);
+        line_errors.extend(validate(
+            &entries,
+            &linear_changes(),
+            &HashMap::new(),
+            false,
+        ));
+        assert_eq!(line_errors.len(), 2);
+
+        let annotated = annotate_errors(content, line_errors);
+        let lines: Vec<&str> = annotated.lines().collect();
+        assert!(lines[0].starts_with(ERROR_PREFIX));
+        assert!(lines[1].starts_with(ERROR_PREFIX));
+        assert_eq!(lines[2], "# header");
+        assert_eq!(lines[3], "pr,aaa,First");
+        assert!(lines[4].starts_with("# ERROR: unknown action 'merge'"));
+        assert_eq!(lines[5], "merge,bbb,Second");
+        assert!(lines[6].starts_with("# ERROR: change 'zzz'"));
+        assert_eq!(lines[7], "pr,zzz,Unknown");
+
+        // The errors are comments, so they parse the same, and stripping them restores the file
+        assert_eq!(parse_stack_file(&annotated).1.len(), 1);
+        assert_eq!(strip_errors(&annotated), content);
+    }
+
+    #[test]
+    fn notes_name_other_authors_and_remote_only_bookmarks() {
+        let mut change = change("aaa", "trunk");
+        change.empty = true;
+        change.bookmarks = vec!["feat-a".to_string()];
+        change.remote_bookmarks = ["feat-a@origin", "feat-old@upstream", "feat-a@git"]
+            .iter()
+            .map(|bookmark| {
+                let (name, remote) = bookmark.split_once('@').unwrap();
+                jj::RemoteBookmark {
+                    name: name.to_string(),
+                    remote: remote.to_string(),
+                }
+            })
+            .collect();
+
+        assert_eq!(
+            state_notes(&change, Some("me@example.com")),
+            [
+                "empty",
+                "by A <a@example.com>",
+                "feat-old@upstream has no local bookmark, track it with jj to use it",
+            ]
+        );
+        assert_eq!(state_notes(&change, Some("a@example.com")).len(), 2);
+    }
+
+    fn round_trip(fields: &[&str]) {
+        let line = write_record(fields);
+        assert_eq!(parse_record(&line).unwrap(), fields, "line: {line}");
+    }
+
+    #[test]
+    fn record_round_trips_plain_fields() {
+        round_trip(&["pr", "kxqpmnrs", "Add the parser", "feat-parser"]);
+    }
+
+    #[test]
+    fn record_round_trips_commas_and_quotes() {
+        round_trip(&["pr", "kxqpmnrs", "Fix foo, bar and \"baz\"", "feat"]);
+        assert_eq!(
+            write_record(&["pr", "abc", "a, \"b\""]),
+            r#"pr,abc,"a, ""b""""#
+        );
+    }
+
+    #[test]
+    fn record_round_trips_surrounding_spaces() {
+        round_trip(&["pr", "kxqpmnrs", "  indented title ", "feat"]);
+    }
+
+    #[test]
+    fn record_round_trips_empty_fields() {
+        round_trip(&["pr", "kxqpmnrs", "", ""]);
+        round_trip(&[""]);
+    }
+
+    #[test]
+    fn record_round_trips_leading_hash() {
+        round_trip(&["pr", "kxqpmnrs", "# not a comment", "feat"]);
+    }
+
+    #[test]
+    fn record_ignores_spaces_around_unquoted_fields() {
+        assert_eq!(
+            parse_record(" pr , abc ,  Title ,feat ").unwrap(),
+            ["pr", "abc", "Title", "feat"]
+        );
+    }
+
+    #[test]
+    fn record_rejects_unterminated_quote() {
+        let error = parse_record(r#"pr,abc,"Title, without end"#).unwrap_err();
+        assert_eq!(error, "unterminated quote in column 3");
+    }
+
+    #[test]
+    fn record_rejects_text_after_closing_quote() {
+        assert!(parse_record(r#"pr,"abc"x,Title"#).is_err());
+    }
+
+    #[test]
+    fn record_rejects_quote_in_unquoted_field() {
+        assert!(parse_record(r#"pr,abc,Say "hi""#).is_err());
+    }
 }
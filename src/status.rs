@@ -0,0 +1,199 @@
+use anyhow::Result;
+use owo_colors::{OwoColorize, Style};
+use std::io::IsTerminal;
+
+use crate::forge::{Checks, Forge, PrState, PrStatus, Review};
+use crate::jj::{self, BookmarkTarget, Change};
+use crate::stack;
+
+/// Length of the change id prefix shown for each change, the same as jj's `short()`
+const CHANGE_ID_LENGTH: usize = 12;
+/// Length of the commit id prefix, the same as jj shows in `jj log`
+const COMMIT_ID_LENGTH: usize = 8;
+
+/// Print the stack as a tree in the order of the stack file, base branch on top, with the PR
+/// of every change, its review and CI status, and whether its bookmark still has to be pushed
+pub fn show(forge: &dyn Forge, changes: &[Change], base_branch: &str, remote: &str) -> Result<()> {
+    let bookmarks: Vec<&str> = changes
+        .iter()
+        .flat_map(|change| change.bookmarks.iter().map(String::as_str))
+        .collect();
+    let statuses = forge.fetch_pr_statuses(&bookmarks)?;
+    let targets = jj::bookmark_targets(remote)?;
+
+    let palette = Palette::new();
+    println!("◆  {}", base_branch.style(palette.bookmark()));
+    println!("│");
+
+    let lines = stack::layout(changes);
+    for (index, (change, tree)) in lines.iter().enumerate() {
+        let indent = stack::continuation(tree);
+        let next = lines.get(index + 1);
+        // The stem below a change leads to its children, which come right after it
+        let stem = match next {
+            Some((_, next_tree)) if next_tree.starts_with(&indent) => "│",
+            _ => " ",
+        };
+
+        // Of several bookmarks, show the one with a PR
+        let bookmark = change
+            .bookmarks
+            .iter()
+            .find(|bookmark| statuses.contains_key(*bookmark))
+            .or(change.bookmarks.first());
+
+        let change_id = change
+            .change_id
+            .get(..CHANGE_ID_LENGTH)
+            .unwrap_or(&change.change_id);
+        let title = if change.title().is_empty() {
+            "(no description set)".style(palette.dimmed()).to_string()
+        } else {
+            change.title().to_string()
+        };
+        let commit_id = change
+            .commit_id
+            .get(..COMMIT_ID_LENGTH)
+            .unwrap_or(&change.commit_id);
+        println!(
+            "{tree}○  {} {}  {title}",
+            change_id.style(palette.change_id()),
+            commit_id.style(palette.commit_id())
+        );
+
+        let details = match bookmark {
+            Some(bookmark) => bookmark_details(
+                bookmark,
+                statuses.get(bookmark),
+                targets.get(bookmark),
+                &palette,
+            ),
+            None => vec!["no bookmark".style(palette.dimmed()).to_string()],
+        };
+        println!("{indent}{stem}  {}", details.join("  "));
+        if let Some(status) = bookmark.and_then(|bookmark| statuses.get(bookmark)) {
+            println!("{indent}{stem}  {}", status.url.style(palette.dimmed()));
+        }
+        if next.is_some() {
+            println!("{}", format!("{indent}{stem}").trim_end());
+        }
+    }
+
+    Ok(())
+}
+
+/// The bookmark, its PR and push state, as separately colored words
+fn bookmark_details(
+    bookmark: &str,
+    status: Option<&PrStatus>,
+    target: Option<&BookmarkTarget>,
+    palette: &Palette,
+) -> Vec<String> {
+    let mut details = vec![bookmark.style(palette.bookmark()).to_string()];
+
+    match status {
+        Some(status) => {
+            details.push(
+                format!("#{}", status.number)
+                    .style(palette.bold())
+                    .to_string(),
+            );
+            details.push(match status.state {
+                PrState::Open => "open".style(palette.good()).to_string(),
+                PrState::Draft => "draft".style(palette.dimmed()).to_string(),
+                PrState::Merged => "merged".style(palette.merged()).to_string(),
+                PrState::Closed => "closed".style(palette.bad()).to_string(),
+            });
+            if let Some(review) = status.review {
+                details.push(match review {
+                    Review::Approved => "approved".style(palette.good()).to_string(),
+                    Review::ChangesRequested => {
+                        "changes requested".style(palette.bad()).to_string()
+                    }
+                    Review::Required => "review required".style(palette.pending()).to_string(),
+                });
+            }
+            if let Some(checks) = status.checks {
+                details.push(match checks {
+                    Checks::Passing => "✓ checks".style(palette.good()).to_string(),
+                    Checks::Failing => "✗ checks".style(palette.bad()).to_string(),
+                    Checks::Pending => "● checks".style(palette.pending()).to_string(),
+                });
+            }
+        }
+        None => details.push("no PR".style(palette.dimmed()).to_string()),
+    }
+
+    // A conflicted local bookmark has no single target to compare
+    if let Some(BookmarkTarget {
+        local: Some(local),
+        remote,
+    }) = target
+    {
+        match remote {
+            None => details.push("not pushed".style(palette.pending()).to_string()),
+            Some(remote) if remote != local => {
+                details.push("remote behind".style(palette.pending()).to_string())
+            }
+            Some(_) => {}
+        }
+    }
+    details
+}
+
+/// Styles for the status tree, plain when stdout is not a terminal or `NO_COLOR` is set
+struct Palette {
+    enabled: bool,
+}
+
+impl Palette {
+    fn new() -> Self {
+        Palette {
+            enabled: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    fn style(&self, style: Style) -> Style {
+        if self.enabled {
+            style
+        } else {
+            Style::new()
+        }
+    }
+
+    fn change_id(&self) -> Style {
+        self.style(Style::new().magenta().bold())
+    }
+
+    fn commit_id(&self) -> Style {
+        self.style(Style::new().blue())
+    }
+
+    fn bookmark(&self) -> Style {
+        self.style(Style::new().magenta())
+    }
+
+    fn bold(&self) -> Style {
+        self.style(Style::new().bold())
+    }
+
+    fn dimmed(&self) -> Style {
+        self.style(Style::new().dimmed())
+    }
+
+    fn good(&self) -> Style {
+        self.style(Style::new().green())
+    }
+
+    fn bad(&self) -> Style {
+        self.style(Style::new().red())
+    }
+
+    fn pending(&self) -> Style {
+        self.style(Style::new().yellow())
+    }
+
+    fn merged(&self) -> Style {
+        self.style(Style::new().blue())
+    }
+}
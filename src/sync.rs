@@ -0,0 +1,188 @@
+use anyhow::Result;
+use log::debug;
+use std::collections::HashMap;
+
+use crate::forge::{Forge, PrState};
+use crate::jj::{self, Change};
+use crate::plan::{Branch, Operation, Plan, StackPr};
+use crate::stack;
+
+/// Work out how to clean up the stack after PRs at its bottom were merged.
+///
+/// `changes` are in jj log order, newest first, and were read before fetching so that
+/// bookmarks the fetch deleted are still known. Only merged PRs at the very bottom of the
+/// stack are cleaned up; a merged PR above an open one went into that PR's branch, not trunk.
+///
+/// The merged changes are abandoned and their bookmarks deleted, the rest of the stack is
+/// rebased onto trunk and pushed, the new bottom PR is retargeted to `trunk` and every stack
+/// comment is refreshed. Remote bookmarks are only deleted once no PR targets them anymore,
+/// since deleting the base branch of a PR closes it.
+pub fn build(
+    forge: &dyn Forge,
+    changes: &[Change],
+    trunk: &str,
+    remote: &str,
+    fork_mode: bool,
+) -> Result<Plan> {
+    let bookmarks: Vec<&str> = changes
+        .iter()
+        .flat_map(|change| change.bookmarks.iter().map(String::as_str))
+        .collect();
+    let statuses = forge.fetch_pr_statuses(&bookmarks)?;
+    let merged_bookmarks = |change: &Change| -> Vec<String> {
+        change
+            .bookmarks
+            .iter()
+            .filter(|bookmark| {
+                statuses
+                    .get(*bookmark)
+                    .is_some_and(|status| status.state == PrState::Merged)
+            })
+            .cloned()
+            .collect()
+    };
+
+    // Bottom of the stack first
+    let bottom_up: Vec<&Change> = changes.iter().rev().collect();
+    let merged_count = bottom_up
+        .iter()
+        .take_while(|change| !merged_bookmarks(change).is_empty())
+        .count();
+    let (merged, remaining) = bottom_up.split_at(merged_count);
+
+    for change in remaining {
+        for bookmark in merged_bookmarks(change) {
+            println!(
+                "The PR of '{bookmark}' was merged, but a change below it was not. Leaving {} alone",
+                change.change_id
+            );
+        }
+    }
+
+    if merged.is_empty() {
+        println!("No PR at the bottom of the stack was merged");
+    }
+
+    let mut operations = Vec::new();
+    let targets = jj::bookmark_targets(remote)?;
+
+    // Delete the local bookmarks first, so abandoning does not move them onto other changes
+    let merged_bookmarks: Vec<String> = merged
+        .iter()
+        .flat_map(|change| merged_bookmarks(change))
+        .collect();
+    for bookmark in &merged_bookmarks {
+        if targets.get(bookmark).is_some_and(|t| t.local.is_some()) {
+            operations.push(Operation::DeleteBookmark {
+                bookmark: bookmark.clone(),
+            });
+        }
+    }
+
+    // Changes merged with a merge commit are part of trunk now and stay as they are
+    let merged_ids: Vec<&str> = merged.iter().map(|c| c.change_id.as_str()).collect();
+    let in_trunk = if merged_ids.is_empty() {
+        Vec::new()
+    } else {
+        jj::changes_in_trunk(&merged_ids)?
+    };
+    let abandon: Vec<String> = merged_ids
+        .iter()
+        .filter(|id| !in_trunk.iter().any(|t| t == *id))
+        .map(|id| id.to_string())
+        .collect();
+    if !merged.is_empty() {
+        operations.push(Operation::DropMerged {
+            abandon,
+            rebase: remaining.first().map(|change| change.change_id.clone()),
+        });
+    }
+
+    // Only the changes left that have an open PR are part of the stack
+    let remaining_bookmarks: Vec<&str> = remaining
+        .iter()
+        .flat_map(|change| change.bookmarks.iter().map(String::as_str))
+        .collect();
+    let prs = forge.fetch_prs(&remaining_bookmarks)?;
+    let mut stack = Vec::new();
+    for change in remaining {
+        let Some((bookmark, pr)) = change
+            .bookmarks
+            .iter()
+            .find_map(|bookmark| prs.get(bookmark).map(|pr| (bookmark, pr)))
+        else {
+            debug!("Change {} has no open PR", change.change_id);
+            continue;
+        };
+
+        if !merged.is_empty() {
+            operations.push(Operation::PushBookmark {
+                bookmark: bookmark.clone(),
+                remote: remote.to_string(),
+            });
+        }
+        stack.push(StackPr {
+            change_id: change.change_id.clone(),
+            head: Branch::Named(bookmark.clone()),
+            existing: Some(pr.clone()),
+            parent: stack.last().map(|below: &StackPr| below.change_id.clone()),
+        });
+    }
+
+    // The bottom PR still targets the branch of the merged PR below it
+    if let Some(pr) = stack.first().and_then(|pr| pr.existing.as_ref()) {
+        if pr.base != trunk {
+            operations.push(Operation::ChangeBase {
+                pr_url: pr.url.clone(),
+                from: pr.base.clone(),
+                to: Branch::Named(trunk.to_string()),
+            });
+        }
+    }
+
+    for bookmark in &merged_bookmarks {
+        if targets.get(bookmark).is_some_and(|t| t.remote.is_some()) {
+            operations.push(Operation::DeleteRemoteBookmark {
+                bookmark: bookmark.clone(),
+                remote: remote.to_string(),
+            });
+        }
+    }
+
+    for pr in &stack {
+        operations.push(Operation::UpsertStackComment {
+            change_id: pr.change_id.clone(),
+            existing: pr
+                .existing
+                .as_ref()
+                .and_then(|existing| existing.stack_comment.clone()),
+        });
+    }
+
+    Ok(Plan {
+        operations,
+        stack,
+        fork_mode,
+    })
+}
+
+/// Bail if any change, or trunk, has more than one child in the stack. Syncing a stack
+/// that branches would close the PRs of the other branches along with the merged branch
+/// they target.
+pub fn ensure_linear(changes: &[Change]) -> Result<()> {
+    let mut children: HashMap<Option<&str>, Vec<&str>> = HashMap::new();
+    for (change_id, parent) in stack::stack_parents(changes) {
+        children.entry(parent).or_default().push(change_id);
+    }
+
+    if let Some((parent, mut branches)) = children.into_iter().find(|(_, c)| c.len() > 1) {
+        branches.sort_unstable();
+        anyhow::bail!(
+            "The stack branches at {}, into {}. sync only handles stacks without branches, \
+             rebase the changes into a single line with jj first",
+            parent.unwrap_or("its base"),
+            branches.join(", ")
+        );
+    }
+    Ok(())
+}